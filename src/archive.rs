@@ -0,0 +1,151 @@
+/*
+ *  Transparent decompression of ROM images. Archives are recognised by
+ *  their magic bytes rather than the file extension.
+ */
+use super::cartridge::LoadError;
+use flate2::read::GzDecoder;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/*
+ *  Extract the ROM if data is a zip or gzip archive, otherwise return it
+ *  unchanged. From a zip, the ROM is the entry named entry (either its
+ *  full path or just the file name) or, without a name, the only .gb or
+ *  .gbc entry.
+ */
+pub fn unpack(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    if data.starts_with(&ZIP_MAGIC) {
+        unzip(&data, entry)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+        Ok(rom)
+    } else {
+        Ok(data)
+    }
+}
+
+fn unzip(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+    let mut names = Vec::new();
+    for name in zip.file_names() {
+        let name = name.map_err(archive_error)?;
+        let wanted = match entry {
+            Some(entry) => name == entry || name.rsplit('/').next() == Some(entry),
+            None => is_rom_name(&name),
+        };
+        if wanted {
+            names.push(name.into_owned());
+        }
+    }
+    names.sort();
+    let name = match names.as_slice() {
+        [name] => name,
+        [] => return Err(LoadError::NoRomInArchive),
+        _ => return Err(LoadError::AmbiguousArchive(names)),
+    };
+    let mut file = zip.by_name(name).map_err(archive_error)?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn archive_error(e: zip::result::ZipError) -> LoadError {
+    LoadError::Archive(e.to_string())
+}
+
+fn is_archive_path(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("zip") || ext.eq_ignore_ascii_case("gz"),
+        None => false,
+    }
+}
+
+/*
+ *  Path that files kept next to a ROM (saves, patches) are named after,
+ *  by replacing its extension. Archives lose theirs first, so both
+ *  tetris.gb.gz and tetris.zip give tetris.sav.
+ */
+pub fn base_path(path: &Path) -> PathBuf {
+    if is_archive_path(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+
+    fn bundled() -> Vec<u8> {
+        fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/data/cpu_instrs.zip")).unwrap()
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let rom = vec![0x00, 0xC3, 0x50, 0x01];
+        assert_eq!(unpack(rom.clone(), None).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let rom: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&rom).unwrap();
+        assert_eq!(unpack(gz.finish().unwrap(), None).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_zip_named_entry() {
+        let rom = unpack(bundled(), Some("cpu_instrs/cpu_instrs.gb")).unwrap();
+        assert_eq!(rom.len(), 0x10000);
+        let rom = unpack(bundled(), Some("01-special.gb")).unwrap();
+        assert_eq!(rom.len(), 0x8000);
+        match unpack(bundled(), Some("missing.gb")) {
+            Err(LoadError::NoRomInArchive) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_zip_ambiguous() {
+        match unpack(bundled(), None) {
+            Err(LoadError::AmbiguousArchive(names)) => {
+                assert_eq!(names.len(), 12);
+                assert_eq!(names[0], "cpu_instrs/cpu_instrs.gb");
+            }
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_zip() {
+        let mut data = bundled();
+        data.truncate(100);
+        match unpack(data, None) {
+            Err(LoadError::Archive(_)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_base_path() {
+        let sav = |p: &str| base_path(Path::new(p)).with_extension("sav");
+        assert_eq!(sav("roms/tetris.ZIP"), Path::new("roms/tetris.sav"));
+        assert_eq!(sav("tetris.gb.gz"), Path::new("tetris.sav"));
+        assert_eq!(sav("tetris.gb"), Path::new("tetris.sav"));
+    }
+}
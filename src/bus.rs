@@ -0,0 +1,99 @@
+/*
+ *  The CPU's view of the system. MMUnit is the full Game Boy
+ *  implementation; FlatRam is a plain 64K RAM for testing instructions
+ *  in isolation.
+ *
+ *  The CPU calls tick() once per M-cycle, before each read or write and
+ *  for each internal delay, so implementations can advance their
+ *  peripherals in lockstep.
+ */
+pub use super::mmu::Interrupt;
+
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+    fn tick(&mut self);
+
+    /*
+     *  Read the opcode of the next instruction. Separate from read() so
+     *  implementations can tell execution from data accesses.
+     */
+    fn fetch(&mut self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    /*
+     *  Interrupts that are both requested (IF) and enabled (IE).
+     */
+    fn pending_interrupts(&self) -> u8 {
+        0
+    }
+
+    /*
+     *  Clear the request bit of an interrupt being serviced.
+     */
+    fn acknowledge_interrupt(&mut self, _i: Interrupt) {}
+
+    /*
+     *  Called by STOP, which resets DIV as a write to FF04 does.
+     */
+    fn reset_divider(&mut self) {}
+
+    /*
+     *  Called by STOP. Perform the CGB speed switch if it was armed and
+     *  return whether it happened.
+     */
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+
+    /*
+     *  Whether a joypad input is held, which wakes the CPU from STOP.
+     */
+    fn joypad_pressed(&self) -> bool {
+        false
+    }
+
+    /*
+     *  ROM, external RAM or WRAM bank mapped at addr, for diagnostics.
+     */
+    fn bank(&self, _addr: u16) -> u16 {
+        0
+    }
+}
+
+/*
+ *  64K of RAM with nothing else attached. IF (0xFF0F) and IE (0xFFFF)
+ *  are plain bytes so interrupts can still be exercised.
+ */
+pub struct FlatRam {
+    pub data: Vec<u8>,
+}
+
+impl Default for FlatRam {
+    fn default() -> FlatRam {
+        FlatRam {
+            data: vec![0; 0x10000],
+        }
+    }
+}
+
+impl Bus for FlatRam {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.data[addr as usize] = val;
+    }
+
+    fn tick(&mut self) {}
+
+    fn pending_interrupts(&self) -> u8 {
+        self.data[0xFF0F] & self.data[0xFFFF] & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, i: Interrupt) {
+        self.data[0xFF0F] &= !i.mask();
+    }
+}
@@ -0,0 +1,350 @@
+/*
+ *  Cartridge header, 0100-014F:
+ *      0100-0103   Entry point
+ *      0104-0133   Nintendo logo
+ *      0134-0143   Title. Newer carts use 0134-013E, with a manufacturer
+ *                  code in 013F-0142 and the CGB flag in 0143
+ *      0144-0145   New licensee code, if 014B is 0x33
+ *      0146        SGB flag
+ *      0147        Cartridge type
+ *      0148        ROM size
+ *      0149        RAM size
+ *      014A        Destination code
+ *      014B        Old licensee code
+ *      014C        Version
+ *      014D        Header checksum over 0134-014C
+ *      014E-014F   Global checksum over the whole ROM, big endian
+ */
+use super::{ram_size, LOGO, ROM_BANK_SIZE};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CgbSupport {
+    #[default]
+    Dmg,
+    Compatible,
+    Only,
+}
+
+/*
+ *  Bank controller named by the cartridge type byte.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Huc1,
+    Huc3,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ROM {
+    pub filename: String,
+    pub title: String,
+    pub manufacturer: String, // Empty on carts without one
+    pub cgb: CgbSupport,
+    pub new_licensee: String,
+    pub old_licensee: u8,
+    pub sgb: bool,
+    pub cart_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination: u8,
+    pub version: u8,
+    pub logo_ok: bool,
+    pub header_checksum: u8,
+    pub header_checksum_ok: bool,
+    pub global_checksum: u16,
+    pub global_checksum_ok: bool,
+}
+
+impl ROM {
+    /*
+     *  Parse the header of a ROM image. Bytes past the end of a truncated
+     *  image read as 0.
+     */
+    pub fn parse(rom: &[u8]) -> ROM {
+        let b = |addr: usize| rom.get(addr).copied().unwrap_or(0);
+        let text = |from: usize, to: usize| -> String {
+            (from..to)
+                .map(b)
+                .take_while(|&c| c != 0)
+                .map(|c| {
+                    if c.is_ascii_graphic() || c == b' ' {
+                        c as char
+                    } else {
+                        '?'
+                    }
+                })
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        };
+
+        let cgb = match b(0x143) {
+            0xC0 => CgbSupport::Only,
+            f if f & 0x80 != 0 => CgbSupport::Compatible,
+            _ => CgbSupport::Dmg,
+        };
+        let has_manufacturer = cgb != CgbSupport::Dmg
+            && (0x13F..0x143).all(|i| b(i).is_ascii_uppercase() || b(i).is_ascii_digit());
+        let title_end = match (cgb, has_manufacturer) {
+            (_, true) => 0x13F,
+            (CgbSupport::Dmg, _) => 0x144,
+            _ => 0x143,
+        };
+
+        let header_checksum = b(0x14D);
+        let computed = (0x134..=0x14C).fold(0u8, |x, i| x.wrapping_sub(b(i)).wrapping_sub(1));
+        let global_checksum = u16::from(b(0x14E)) << 8 | u16::from(b(0x14F));
+        let sum = rom
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+            .fold(0u16, |x, (_, &v)| x.wrapping_add(u16::from(v)));
+
+        ROM {
+            filename: String::new(),
+            title: text(0x134, title_end),
+            manufacturer: if has_manufacturer {
+                text(0x13F, 0x143)
+            } else {
+                String::new()
+            },
+            cgb,
+            new_licensee: text(0x144, 0x146),
+            old_licensee: b(0x14B),
+            sgb: b(0x146) == 0x03,
+            cart_type: b(0x147),
+            rom_size: b(0x148),
+            ram_size: b(0x149),
+            destination: b(0x14A),
+            version: b(0x14C),
+            logo_ok: (0x104..0x134).map(b).eq(LOGO.iter().copied()),
+            header_checksum,
+            header_checksum_ok: header_checksum == computed,
+            global_checksum,
+            global_checksum_ok: global_checksum == sum,
+        }
+    }
+
+    pub fn mapper(&self) -> Option<Mapper> {
+        match self.cart_type {
+            0x00 | 0x08 | 0x09 => Some(Mapper::RomOnly),
+            0x01..=0x03 => Some(Mapper::Mbc1),
+            0x05 | 0x06 => Some(Mapper::Mbc2),
+            0x0F..=0x13 => Some(Mapper::Mbc3),
+            0x19..=0x1E => Some(Mapper::Mbc5),
+            0xFE => Some(Mapper::Huc3),
+            0xFF => Some(Mapper::Huc1),
+            _ => None,
+        }
+    }
+
+    pub fn cart_type_name(&self) -> &'static str {
+        match self.cart_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "UNKNOWN",
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cart_type,
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFE | 0xFF
+        )
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.cart_type, 0x0F | 0x10 | 0xFE)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.cart_type, 0x1C..=0x1E | 0x22)
+    }
+
+    /*
+     *  ROM size in bytes from the header, 32K << code.
+     */
+    pub fn rom_bytes(&self) -> usize {
+        match self.rom_size {
+            0x00..=0x08 => (2 * ROM_BANK_SIZE) << self.rom_size,
+            _ => 0,
+        }
+    }
+
+    /*
+     *  External RAM size in bytes. MBC2's built-in RAM is not counted.
+     */
+    pub fn ram_bytes(&self) -> usize {
+        ram_size(self.ram_size)
+    }
+
+    /*
+     *  Licensee code, from 0144-0145 when 014B says to look there.
+     */
+    pub fn licensee(&self) -> String {
+        if self.old_licensee == 0x33 {
+            self.new_licensee.clone()
+        } else {
+            format!("{:02X}", self.old_licensee)
+        }
+    }
+}
+
+fn ok(valid: bool) -> &'static str {
+    if valid {
+        "ok"
+    } else {
+        "BAD"
+    }
+}
+
+impl fmt::Display for ROM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cgb = match self.cgb {
+            CgbSupport::Dmg => "no",
+            CgbSupport::Compatible => "compatible",
+            CgbSupport::Only => "required",
+        };
+        let destination = match self.destination {
+            0x00 => "Japan",
+            _ => "Overseas",
+        };
+        writeln!(f, "ROM:")?;
+        writeln!(f, "\tFilename: {}", self.filename)?;
+        writeln!(f, "\tTitle: {}", self.title)?;
+        writeln!(f, "\tManufacturer: {}", self.manufacturer)?;
+        writeln!(f, "\tCGB: {}", cgb)?;
+        writeln!(f, "\tLicensee: {}", self.licensee())?;
+        writeln!(f, "\tSGB: {}", if self.sgb { "yes" } else { "no" })?;
+        writeln!(
+            f,
+            "\tType: ${:02X} {}",
+            self.cart_type,
+            self.cart_type_name()
+        )?;
+        writeln!(f, "\tROM size: {}K", self.rom_bytes() / 1024)?;
+        writeln!(f, "\tRAM size: {}K", self.ram_bytes() / 1024)?;
+        writeln!(f, "\tDestination: {}", destination)?;
+        writeln!(f, "\tVersion: {}", self.version)?;
+        writeln!(f, "\tLogo: {}", ok(self.logo_ok))?;
+        writeln!(
+            f,
+            "\tHeader checksum: ${:02X} {}",
+            self.header_checksum,
+            ok(self.header_checksum_ok)
+        )?;
+        write!(
+            f,
+            "\tGlobal checksum: ${:04X} {}",
+            self.global_checksum,
+            ok(self.global_checksum_ok)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::rom_image;
+
+    fn image(title: &[u8], cgb: u8) -> Vec<u8> {
+        rom_image(title, cgb, 0x1B)
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let h = ROM::parse(&image(b"TETRIS", 0x00));
+        assert_eq!(h.title, "TETRIS");
+        assert_eq!(h.manufacturer, "");
+        assert_eq!(h.cgb, CgbSupport::Dmg);
+        assert_eq!(h.licensee(), "01");
+        assert!(h.sgb);
+        assert_eq!(h.mapper(), Some(Mapper::Mbc5));
+        assert!(h.has_battery());
+        assert!(!h.has_rumble());
+        assert_eq!(h.rom_bytes(), 0x8000);
+        assert_eq!(h.ram_bytes(), 0);
+        assert_eq!(h.destination, 0x01);
+        assert_eq!(h.version, 0x02);
+        assert!(h.logo_ok);
+        assert!(h.header_checksum_ok);
+        assert!(h.global_checksum_ok);
+    }
+
+    #[test]
+    fn test_cgb_title() {
+        let h = ROM::parse(&image(b"POKEMON_SLVAAXE", 0x80));
+        assert_eq!(h.title, "POKEMON_SLV");
+        assert_eq!(h.manufacturer, "AAXE");
+        assert_eq!(h.cgb, CgbSupport::Compatible);
+        let h = ROM::parse(&image(b"ZELDA", 0xC0));
+        assert_eq!(h.title, "ZELDA");
+        assert_eq!(h.manufacturer, "");
+        assert_eq!(h.cgb, CgbSupport::Only);
+    }
+
+    #[test]
+    fn test_bad_checksums() {
+        let mut rom = image(b"TEST", 0x00);
+        rom[0x4000] ^= 0xFF;
+        let h = ROM::parse(&rom);
+        assert!(h.header_checksum_ok);
+        assert!(!h.global_checksum_ok);
+        rom[0x134] = b'X';
+        assert!(!ROM::parse(&rom).header_checksum_ok);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let h = ROM::parse(&[0; 0x20]);
+        assert_eq!(h.title, "");
+        assert!(!h.logo_ok);
+        assert_eq!(h.mapper(), Some(Mapper::RomOnly));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut h = ROM::parse(&image(b"TETRIS", 0x00));
+        h.filename = "tetris.gb".to_string();
+        let s = h.to_string();
+        assert!(s.contains("\tFilename: tetris.gb\n"));
+        assert!(s.contains("\tTitle: TETRIS\n"));
+        assert!(s.contains("\tType: $1B MBC5+RAM+BATTERY\n"));
+        assert!(s.contains("\tROM size: 32K\n"));
+        assert!(s.contains("\tHeader checksum: $"));
+        assert!(!s.contains("BAD"));
+    }
+}
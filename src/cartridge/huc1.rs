@@ -0,0 +1,135 @@
+/*
+ *  Hudson HuC1: up to 1M of ROM, 32K of RAM and an infrared LED and
+ *  receiver for talking to another Game Boy.
+ *
+ *      0000-1FFF   0x0E maps the IR port at A000-BFFF, anything else RAM
+ *      2000-3FFF   6-bit ROM bank
+ *      4000-5FFF   RAM bank 0-3
+ *
+ *  There is no RAM enable. In IR mode, writing bit 0 switches the LED on
+ *  or off and reads return 0xC0, with bit 0 set when light is seen. No
+ *  other device is ever connected, so it never is.
+ */
+use super::{load_ram, ram_bank_number, ram_index, rom_byte, Cartridge, ROM_BANK_SIZE};
+
+pub struct Huc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ir_mode: bool,
+    led: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl Huc1 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Huc1 {
+        Huc1 {
+            rom,
+            ram,
+            ir_mode: false,
+            led: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => usize::from(self.rom_bank),
+        }
+    }
+}
+
+impl Cartridge for Huc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ir_mode = (val & 0x0F) == 0x0E,
+            0x2000..=0x3FFF => self.rom_bank = val & 0x3F,
+            0x4000..=0x5FFF => self.ram_bank = val & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if self.ir_mode {
+            return 0xC0;
+        }
+        match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+            Some(i) => self.ram[i],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        if self.ir_mode {
+            self.led = (val & 0x01) != 0;
+            return false;
+        }
+        match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+            Some(i) => {
+                self.ram[i] = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+
+    fn ram_bank(&self) -> u16 {
+        ram_bank_number(&self.ram, usize::from(self.ram_bank))
+    }
+
+    fn infrared(&self) -> bool {
+        self.led
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+
+    #[test]
+    fn test_banking() {
+        let mut m = Huc1::new(numbered_rom(64), vec![0; 0x8000]);
+        m.write_rom(0x2000, 0x45);
+        assert_eq!(m.read_rom(0x4000), 0x05);
+        assert_eq!(m.rom_bank(0x4000), 0x05);
+        m.write_rom(0x4000, 0x02);
+        m.write_ram(0xA123, 0x99); // No enable needed
+        assert_eq!(m.ram[0x4123], 0x99);
+        assert_eq!(m.save_data()[0x4123], 0x99);
+    }
+
+    #[test]
+    fn test_infrared() {
+        let mut m = Huc1::new(numbered_rom(4), vec![0; 0x2000]);
+        m.write_ram(0xA000, 0x11);
+        m.write_rom(0x0000, 0x0E);
+        assert_eq!(m.read_ram(0xA000), 0xC0);
+        m.write_ram(0xA000, 0x01);
+        assert!(m.infrared());
+        m.write_rom(0x0000, 0x00);
+        assert_eq!(m.read_ram(0xA000), 0x11); // RAM untouched by the LED
+        m.write_rom(0x0000, 0x0E);
+        m.write_ram(0xA000, 0x00);
+        assert!(!m.infrared());
+    }
+}
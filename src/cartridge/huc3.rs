@@ -0,0 +1,351 @@
+/*
+ *  Hudson HuC3: up to 2M of ROM, 32K of RAM, a real-time clock, an
+ *  infrared port and a piezo speaker.
+ *
+ *      0000-1FFF   Selects what A000-BFFF maps, by the low nibble:
+ *                      0   RAM, read only
+ *                      A   RAM, read/write
+ *                      B   Clock command (write)
+ *                      C   Clock response (read)
+ *                      D   Clock ready flag, always set
+ *                      E   IR port, as on HuC1
+ *      2000-3FFF   7-bit ROM bank
+ *      4000-5FFF   RAM bank 0-3
+ *
+ *  The clock is driven through 256 nibbles of scratch memory. A command
+ *  byte holds the command in bits 4-6 and an argument in bits 0-3:
+ *      1   Read the nibble at the address into the response, then
+ *          increment the address
+ *      3   Write the argument at the address and increment it
+ *      4   Set the low nibble of the address
+ *      5   Set the high nibble of the address
+ *      6   Argument 0 copies the clock to nibbles 00-05, 1 sets it
+ *          from them, 2 answers 1. Others drive the speaker, which
+ *          isn't emulated
+ *  The time is stored as a 12-bit minute of the day followed by a 12-bit
+ *  day count, least significant nibble first.
+ *
+ *  Saves append a 17-byte footer, little endian: a 64-bit UNIX timestamp,
+ *  the minutes and days as 16-bit words, then the alarm minutes, alarm
+ *  days and alarm enable (always zero, the alarm isn't emulated).
+ */
+use super::rtc::{RtcClock, TimeSource};
+use super::{load_ram, ram_bank_number, ram_index, rom_byte, Cartridge, ROM_BANK_SIZE};
+use std::convert::TryInto;
+
+const MINUTES_PER_DAY: u16 = 1440;
+const FOOTER_SIZE: usize = 17;
+
+/*
+ *  Minutes and days counted by the clock. Seconds are kept so partial
+ *  minutes aren't lost, but can't be read.
+ */
+#[derive(Default)]
+struct Clock {
+    seconds: u8,
+    minutes: u16, // Minute of the day, 0-1439
+    days: u16,    // 12 bits
+    source: TimeSource,
+}
+
+impl Clock {
+    fn advance(&mut self, secs: u64) {
+        let total = u64::from(self.seconds) + secs;
+        let minutes = u64::from(self.minutes) + total / 60;
+        let days = u64::from(self.days) + minutes / u64::from(MINUTES_PER_DAY);
+        self.seconds = (total % 60) as u8;
+        self.minutes = (minutes % u64::from(MINUTES_PER_DAY)) as u16;
+        self.days = (days & 0xFFF) as u16;
+    }
+
+    fn tick(&mut self, t_cycles: u32) {
+        let secs = self.source.tick(t_cycles);
+        if secs > 0 {
+            self.advance(u64::from(secs));
+        }
+    }
+
+    /*
+     *  Bring the clock up to date with the host time.
+     */
+    fn sync(&mut self) {
+        let secs = self.source.sync();
+        self.advance(secs);
+    }
+
+    fn set(&mut self, minutes: u16, days: u16) {
+        self.sync();
+        self.seconds = 0;
+        self.minutes = minutes % MINUTES_PER_DAY;
+        self.days = days & 0xFFF;
+        self.source.reset();
+    }
+}
+
+pub struct Huc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mode: u8, // Low nibble of the last write to 0000-1FFF
+    rom_bank: u8,
+    ram_bank: u8,
+    led: bool,
+    clock: Clock,
+    nibbles: [u8; 256],
+    address: u8,
+    response: u8, // Last command in bits 4-6, result in bits 0-3
+}
+
+impl Huc3 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Huc3 {
+        Huc3 {
+            rom,
+            ram,
+            mode: 0,
+            rom_bank: 1,
+            ram_bank: 0,
+            led: false,
+            clock: Clock::default(),
+            nibbles: [0; 256],
+            address: 0,
+            response: 0,
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => usize::from(self.rom_bank),
+        }
+    }
+
+    fn command(&mut self, val: u8) {
+        let arg = val & 0x0F;
+        let mut result = 0;
+        match (val >> 4) & 0x07 {
+            0x1 => {
+                result = self.nibbles[usize::from(self.address)];
+                self.address = self.address.wrapping_add(1);
+            }
+            0x3 => {
+                self.nibbles[usize::from(self.address)] = arg;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = (self.address & 0xF0) | arg,
+            0x5 => self.address = (self.address & 0x0F) | (arg << 4),
+            0x6 => match arg {
+                0x0 => {
+                    self.clock.sync();
+                    let (minutes, days) = (self.clock.minutes, self.clock.days);
+                    for i in 0..3 {
+                        self.nibbles[i] = (minutes >> (4 * i)) as u8 & 0x0F;
+                        self.nibbles[3 + i] = (days >> (4 * i)) as u8 & 0x0F;
+                    }
+                }
+                0x1 => {
+                    let word = |n: &[u8]| (0..3).fold(0, |w, i| w | u16::from(n[i]) << (4 * i));
+                    let (minutes, days) = (word(&self.nibbles[0..3]), word(&self.nibbles[3..6]));
+                    self.clock.set(minutes, days);
+                }
+                0x2 => result = 0x1,
+                _ => {}
+            },
+            _ => {}
+        }
+        self.response = (val & 0x70) | result;
+    }
+}
+
+impl Cartridge for Huc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.mode = val & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = val & 0x7F,
+            0x4000..=0x5FFF => self.ram_bank = val & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.mode {
+            0x0 | 0xA => match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+                Some(i) => self.ram[i],
+                None => 0xFF,
+            },
+            0xC => 0x80 | self.response,
+            0xD => 0xFF,
+            0xE => 0xC0,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        match self.mode {
+            0xA => match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+                Some(i) => {
+                    self.ram[i] = val;
+                    true
+                }
+                None => false,
+            },
+            0xB => {
+                // Only setting the clock changes what is saved
+                self.command(val);
+                val == 0x61
+            }
+            0xE => {
+                self.led = (val & 0x01) != 0;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.clock.sync();
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&TimeSource::timestamp().to_le_bytes());
+        data.extend_from_slice(&self.clock.minutes.to_le_bytes());
+        data.extend_from_slice(&self.clock.days.to_le_bytes());
+        data.extend_from_slice(&[0; 5]);
+        data
+    }
+
+    /*
+     *  Restore RAM and the clock footer. When following the host clock,
+     *  the time the save spent on disk is added on.
+     */
+    fn load_save(&mut self, data: &[u8]) {
+        let ram_len = self.ram.len().min(data.len());
+        load_ram(&mut self.ram, &data[..ram_len]);
+        let footer = match data.get(ram_len..ram_len + FOOTER_SIZE) {
+            Some(footer) => footer,
+            None => return,
+        };
+        let word = |i: usize| u16::from_le_bytes(footer[i..i + 2].try_into().unwrap());
+        let saved = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let clock = &mut self.clock;
+        clock.seconds = 0;
+        clock.minutes = word(8) % MINUTES_PER_DAY;
+        clock.days = word(10) & 0xFFF;
+        let secs = clock.source.restore(saved);
+        clock.advance(secs);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+
+    fn ram_bank(&self) -> u16 {
+        ram_bank_number(&self.ram, usize::from(self.ram_bank))
+    }
+
+    fn tick(&mut self, t_cycles: u32) {
+        self.clock.tick(t_cycles);
+    }
+
+    fn set_rtc_clock(&mut self, clock: RtcClock) {
+        let secs = self.clock.source.set_clock(clock);
+        self.clock.advance(secs);
+    }
+
+    fn infrared(&self) -> bool {
+        self.led
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+    use crate::cartridge::rtc::CYCLES_PER_SECOND;
+
+    /*
+     *  Run a clock command, returning the response nibble.
+     */
+    fn command(m: &mut Huc3, cmd: u8) -> u8 {
+        m.write_rom(0x0000, 0x0B);
+        m.write_ram(0xA000, cmd);
+        m.write_rom(0x0000, 0x0C);
+        let response = m.read_ram(0xA000);
+        assert_eq!(response & 0xF0, 0x80 | (cmd & 0x70));
+        response & 0x0F
+    }
+
+    /*
+     *  Minute of the day and day count, read the way games do.
+     */
+    fn read_time(m: &mut Huc3) -> (u16, u16) {
+        command(m, 0x60);
+        command(m, 0x40);
+        command(m, 0x50);
+        let mut n = [0u16; 6];
+        for x in n.iter_mut() {
+            *x = u16::from(command(m, 0x10));
+        }
+        (n[0] | n[1] << 4 | n[2] << 8, n[3] | n[4] << 4 | n[5] << 8)
+    }
+
+    fn set_time(m: &mut Huc3, minutes: u16, days: u16) {
+        command(m, 0x40);
+        command(m, 0x50);
+        for i in 0..3 {
+            command(m, 0x30 | (minutes >> (4 * i)) as u8 & 0x0F);
+        }
+        for i in 0..3 {
+            command(m, 0x30 | (days >> (4 * i)) as u8 & 0x0F);
+        }
+        command(m, 0x61);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut m = Huc3::new(numbered_rom(128), vec![0; 0x8000]);
+        m.write_rom(0x2000, 0x7F);
+        assert_eq!(m.read_rom(0x4000), 0x7F);
+        m.write_rom(0x4000, 0x01);
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA000, 0x12);
+        m.write_rom(0x0000, 0x00);
+        m.write_ram(0xA000, 0x34); // Read only
+        assert_eq!(m.read_ram(0xA000), 0x12);
+        assert_eq!(m.ram[0x2000], 0x12);
+        m.write_rom(0x0000, 0x0E);
+        m.write_ram(0xA000, 0x01);
+        assert!(m.infrared());
+        assert_eq!(m.read_ram(0xA000), 0xC0);
+    }
+
+    #[test]
+    fn test_clock_commands() {
+        let mut m = Huc3::new(numbered_rom(4), vec![0; 0x2000]);
+        assert_eq!(command(&mut m, 0x62), 0x1);
+        m.write_rom(0x0000, 0x0D);
+        assert_eq!(m.read_ram(0xA000) & 0x01, 0x01);
+        set_time(&mut m, 1439, 0xABC);
+        assert_eq!(read_time(&mut m), (1439, 0xABC));
+        m.tick(60 * CYCLES_PER_SECOND);
+        assert_eq!(read_time(&mut m), (0, 0xABD));
+    }
+
+    #[test]
+    fn test_save_footer() {
+        let mut m = Huc3::new(numbered_rom(4), vec![0; 0x2000]);
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA000, 0x77);
+        set_time(&mut m, 600, 3);
+        let data = m.save_data();
+        assert_eq!(data.len(), 0x2000 + FOOTER_SIZE);
+        assert_eq!(&data[0x2008..0x200C], &[0x58, 0x02, 0x03, 0x00]);
+
+        let mut n = Huc3::new(numbered_rom(4), vec![0; 0x2000]);
+        n.load_save(&data);
+        n.write_rom(0x0000, 0x0A);
+        assert_eq!(n.read_ram(0xA000), 0x77);
+        assert_eq!(read_time(&mut n), (600, 3));
+    }
+}
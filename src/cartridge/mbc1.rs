@@ -0,0 +1,235 @@
+/*
+ *  MBC1: up to 2M of ROM and 32K of RAM.
+ *
+ *      0000-1FFF   RAM enable, 0x0A in the low nibble enables
+ *      2000-3FFF   BANK1, 5-bit ROM bank (0 is treated as 1)
+ *      4000-5FFF   BANK2, 2 bits: ROM bank bits 5-6 or the RAM bank
+ *      6000-7FFF   Mode. In mode 1 BANK2 also applies to 0000-3FFF
+ *                  and to RAM
+ *
+ *  MBC1M multicarts wire BANK2 to bits 4-5 instead and only use the low
+ *  4 bits of BANK1, splitting a 1M ROM into four 256K games.
+ */
+use super::{load_ram, ram_bank_number, ram_index, rom_byte, Cartridge, LOGO, ROM_BANK_SIZE};
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Mbc1 {
+        let multicart = is_multicart(&rom);
+        Mbc1 {
+            rom,
+            ram,
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+            multicart,
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn bank1_mask(&self) -> u8 {
+        if self.multicart {
+            0x0F
+        } else {
+            0x1F
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        let upper = usize::from(self.bank2) << self.bank2_shift();
+        match addr {
+            0x0000..=0x3FFF if self.mode => upper,
+            0x0000..=0x3FFF => 0,
+            _ => upper | usize::from(self.bank1 & self.bank1_mask()),
+        }
+    }
+
+    fn selected_ram_bank(&self) -> usize {
+        if self.mode {
+            usize::from(self.bank2)
+        } else {
+            0
+        }
+    }
+}
+
+/*
+ *  A 1M MBC1 ROM is a multicart if a second game's header (identified
+ *  by the Nintendo logo) starts at bank 0x10.
+ */
+fn is_multicart(rom: &[u8]) -> bool {
+    let header = 0x10 * ROM_BANK_SIZE + 0x104;
+    rom.len() == 0x100000 && rom[header..header + LOGO.len()] == LOGO
+}
+
+impl Cartridge for Mbc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = (val & 0x0F) == 0x0A,
+            0x2000..=0x3FFF => {
+                // The zero check looks at all 5 bits, even on multicarts
+                self.bank1 = val & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = val & 0x03,
+            _ => self.mode = (val & 0x01) != 0,
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_index(&self.ram, self.selected_ram_bank(), addr) {
+            Some(i) => self.ram[i],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_index(&self.ram, self.selected_ram_bank(), addr) {
+            Some(i) => {
+                self.ram[i] = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+
+    fn ram_bank(&self) -> u16 {
+        ram_bank_number(&self.ram, self.selected_ram_bank())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+
+    #[test]
+    fn test_rom_banking() {
+        let mut m = Mbc1::new(numbered_rom(32), Vec::new());
+        assert_eq!(m.read_rom(0x4000), 1);
+        m.write_rom(0x2000, 0x05);
+        assert_eq!(m.read_rom(0x4000), 5);
+        assert_eq!(m.rom_bank(0x4000), 5);
+        m.write_rom(0x2000, 0x25); // Bits above 5 are ignored
+        assert_eq!(m.read_rom(0x4000), 5);
+        assert_eq!(m.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn test_bank_zero_quirk() {
+        let mut m = Mbc1::new(numbered_rom(128), Vec::new());
+        m.write_rom(0x2000, 0x00);
+        assert_eq!(m.read_rom(0x4000), 1);
+        m.write_rom(0x4000, 0x01);
+        m.write_rom(0x2000, 0x00);
+        assert_eq!(m.read_rom(0x4000), 0x21); // 0x20 is unreachable
+        m.write_rom(0x2000, 0x20); // Low 5 bits are zero
+        assert_eq!(m.read_rom(0x4000), 0x21);
+    }
+
+    #[test]
+    fn test_upper_bits_and_mode() {
+        let mut m = Mbc1::new(numbered_rom(128), Vec::new());
+        m.write_rom(0x4000, 0x02);
+        m.write_rom(0x2000, 0x03);
+        assert_eq!(m.read_rom(0x4000), 0x43);
+        assert_eq!(m.read_rom(0x0000), 0x00);
+        m.write_rom(0x6000, 0x01);
+        assert_eq!(m.read_rom(0x0000), 0x40);
+        assert_eq!(m.rom_bank(0x0000), 0x40);
+    }
+
+    #[test]
+    fn test_bank_wraps_rom_size() {
+        let mut m = Mbc1::new(numbered_rom(4), Vec::new());
+        m.write_rom(0x2000, 0x06);
+        assert_eq!(m.read_rom(0x4000), 2);
+    }
+
+    #[test]
+    fn test_ram_enable() {
+        let mut m = Mbc1::new(numbered_rom(4), vec![0; 0x2000]);
+        m.write_ram(0xA000, 0x12);
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA000, 0x12);
+        assert_eq!(m.read_ram(0xA000), 0x12);
+        m.write_rom(0x0000, 0x00);
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+        m.write_rom(0x0000, 0x1A); // Only the low nibble is checked
+        assert_eq!(m.read_ram(0xA000), 0x12);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut m = Mbc1::new(numbered_rom(4), vec![0; 0x8000]);
+        m.write_rom(0x0000, 0x0A);
+        m.write_rom(0x4000, 0x02);
+        m.write_ram(0xA000, 0x11); // Mode 0, always bank 0
+        m.write_rom(0x6000, 0x01);
+        m.write_ram(0xA000, 0x22);
+        assert_eq!(m.ram[0x0000], 0x11);
+        assert_eq!(m.ram[0x4000], 0x22);
+        m.write_rom(0x6000, 0x00);
+        assert_eq!(m.read_ram(0xA000), 0x11);
+    }
+
+    #[test]
+    fn test_multicart() {
+        let mut rom = numbered_rom(64);
+        for bank in [0x00, 0x10, 0x20, 0x30].iter() {
+            let header = bank * ROM_BANK_SIZE + 0x104;
+            rom[header..header + LOGO.len()].copy_from_slice(&LOGO);
+        }
+        let mut m = Mbc1::new(rom, Vec::new());
+        assert!(m.multicart);
+        m.write_rom(0x4000, 0x01);
+        m.write_rom(0x2000, 0x12); // Bit 4 of BANK1 is not connected
+        assert_eq!(m.read_rom(0x4000), 0x12);
+        m.write_rom(0x6000, 0x01);
+        assert_eq!(m.read_rom(0x0000), 0x10);
+        assert!(!Mbc1::new(numbered_rom(64), Vec::new()).multicart);
+    }
+}
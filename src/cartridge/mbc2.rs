@@ -0,0 +1,130 @@
+/*
+ *  MBC2: up to 256K of ROM and a built-in 512x4-bit RAM.
+ *
+ *      0000-3FFF   Register select by address bit 8. Clear: RAM enable,
+ *                  0x0A in the low nibble enables. Set: 4-bit ROM bank
+ *                  (0 is treated as 1)
+ *
+ *  Only the low nibble of each RAM byte is stored; the upper nibble reads
+ *  as 1s. The 512 bytes are echoed across all of A000-BFFF.
+ */
+use super::{load_ram, rom_byte, Cartridge, ROM_BANK_SIZE};
+
+pub const RAM_SIZE: usize = 0x200;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            bank: 1,
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => usize::from(self.bank),
+        }
+    }
+}
+
+impl Cartridge for Mbc2 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram_enabled = (val & 0x0F) == 0x0A,
+            0x0000..=0x3FFF => {
+                self.bank = val & 0x0F;
+                if self.bank == 0 {
+                    self.bank = 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        0xF0 | self.ram[addr as usize & (RAM_SIZE - 1)]
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        if self.ram_enabled {
+            self.ram[addr as usize & (RAM_SIZE - 1)] = val & 0x0F;
+        }
+        self.ram_enabled
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+
+    #[test]
+    fn test_register_select() {
+        let mut m = Mbc2::new(numbered_rom(16));
+        m.write_rom(0x2100, 0x05);
+        assert_eq!(m.read_rom(0x4000), 5);
+        m.write_rom(0x2000, 0x07); // Bit 8 clear, RAM enable register
+        assert_eq!(m.read_rom(0x4000), 5);
+        m.write_rom(0x0100, 0x00);
+        assert_eq!(m.read_rom(0x4000), 1);
+        m.write_rom(0x3FFF, 0x1C); // Only 4 bits are used
+        assert_eq!(m.read_rom(0x4000), 0x0C);
+        assert_eq!(m.rom_bank(0x4000), 0x0C);
+        m.write_rom(0x4100, 0x02); // Ignored
+        assert_eq!(m.read_rom(0x4000), 0x0C);
+    }
+
+    #[test]
+    fn test_ram_nibbles() {
+        let mut m = Mbc2::new(numbered_rom(2));
+        m.write_ram(0xA000, 0x0F);
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA000, 0x5A);
+        assert_eq!(m.read_ram(0xA000), 0xFA);
+        assert_eq!(m.ram[0], 0x0A);
+        m.write_rom(0x0100, 0x0A); // Bit 8 set, ROM bank register
+        assert_eq!(m.read_ram(0xA000), 0xFA);
+    }
+
+    #[test]
+    fn test_ram_echo() {
+        let mut m = Mbc2::new(numbered_rom(2));
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA1FF, 0x03);
+        assert_eq!(m.read_ram(0xA3FF), 0xF3);
+        assert_eq!(m.read_ram(0xBFFF), 0xF3);
+        m.write_ram(0xB000, 0x09);
+        assert_eq!(m.read_ram(0xA000), 0xF9);
+    }
+}
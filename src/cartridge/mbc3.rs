@@ -0,0 +1,213 @@
+/*
+ *  MBC3: up to 2M of ROM, 32K of RAM and an optional real-time clock.
+ *
+ *      0000-1FFF   RAM and RTC enable, 0x0A in the low nibble enables
+ *      2000-3FFF   7-bit ROM bank (0 is treated as 1)
+ *      4000-5FFF   00-03 maps a RAM bank, 08-0C an RTC register
+ *      6000-7FFF   RTC latch
+ */
+use super::rtc::{Rtc, RtcClock};
+use super::{load_ram, ram_bank_number, ram_index, rom_byte, Cartridge, ROM_BANK_SIZE};
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_select: u8, // RAM bank or RTC register
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram,
+            rtc: if has_rtc { Some(Rtc::default()) } else { None },
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => usize::from(self.rom_bank),
+        }
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = (val & 0x0F) == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = val & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_select = val & 0x0F,
+            _ => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(val);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_select {
+            0x00..=0x03 => match ram_index(&self.ram, usize::from(self.ram_select), addr) {
+                Some(i) => self.ram[i],
+                None => 0xFF,
+            },
+            0x08..=0x0C => self
+                .rtc
+                .as_ref()
+                .map_or(0xFF, |rtc| rtc.read(self.ram_select)),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match self.ram_select {
+            0x00..=0x03 => match ram_index(&self.ram, usize::from(self.ram_select), addr) {
+                Some(i) => {
+                    self.ram[i] = val;
+                    true
+                }
+                None => false,
+            },
+            0x08..=0x0C => match self.rtc.as_mut() {
+                Some(rtc) => {
+                    rtc.write(self.ram_select, val);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.rtc.as_mut() {
+            data.extend_from_slice(&rtc.save());
+        }
+        data
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        let ram_len = self.ram.len().min(data.len());
+        load_ram(&mut self.ram, &data[..ram_len]);
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load(&data[ram_len..]);
+        }
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+
+    fn ram_bank(&self) -> u16 {
+        match self.ram_select {
+            0x00..=0x03 => ram_bank_number(&self.ram, usize::from(self.ram_select)),
+            _ => u16::from(self.ram_select),
+        }
+    }
+
+    fn tick(&mut self, t_cycles: u32) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(t_cycles);
+        }
+    }
+
+    fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+
+    #[test]
+    fn test_rom_banking() {
+        let mut m = Mbc3::new(numbered_rom(128), Vec::new(), false);
+        m.write_rom(0x2000, 0x00);
+        assert_eq!(m.read_rom(0x4000), 1);
+        m.write_rom(0x2000, 0x20); // No MBC1 style gap at 0x20
+        assert_eq!(m.read_rom(0x4000), 0x20);
+        m.write_rom(0x2000, 0xFF);
+        assert_eq!(m.read_rom(0x4000), 0x7F);
+        assert_eq!(m.rom_bank(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut m = Mbc3::new(numbered_rom(4), vec![0; 0x8000], false);
+        m.write_rom(0x0000, 0x0A);
+        m.write_rom(0x4000, 0x03);
+        m.write_ram(0xA010, 0x33);
+        assert_eq!(m.ram[0x6010], 0x33);
+        m.write_rom(0x4000, 0x00);
+        assert_eq!(m.read_ram(0xA010), 0x00);
+        m.write_rom(0x4000, 0x08); // No RTC fitted
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_rtc_registers() {
+        let mut m = Mbc3::new(numbered_rom(4), vec![0; 0x2000], true);
+        m.write_rom(0x0000, 0x0A);
+        m.write_rom(0x4000, 0x09);
+        m.write_ram(0xA000, 42);
+        m.write_rom(0x4000, 0x08);
+        m.write_ram(0xA000, 58);
+        for _ in 0..2 * 1_048_576 {
+            m.tick(4);
+        }
+        m.write_rom(0x6000, 0x00);
+        m.write_rom(0x6000, 0x01);
+        assert_eq!(m.read_ram(0xA000), 0);
+        m.write_rom(0x4000, 0x09);
+        assert_eq!(m.read_ram(0xA000), 43);
+        m.write_rom(0x0000, 0x00);
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_save_with_rtc_footer() {
+        let mut m = Mbc3::new(numbered_rom(4), vec![0; 0x2000], true);
+        m.write_rom(0x0000, 0x0A);
+        m.write_ram(0xA000, 0x77);
+        m.write_rom(0x4000, 0x0A);
+        m.write_ram(0xA000, 5);
+        let data = m.save_data();
+        assert_eq!(data.len(), 0x2000 + 48);
+        assert_eq!(data[0x2000 + 8], 5);
+
+        let mut n = Mbc3::new(numbered_rom(4), vec![0; 0x2000], true);
+        n.load_save(&data);
+        n.write_rom(0x0000, 0x0A);
+        assert_eq!(n.read_ram(0xA000), 0x77);
+        n.write_rom(0x4000, 0x0A);
+        assert_eq!(n.read_ram(0xA000), 5);
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ *  MBC5: up to 8M of ROM and 128K of RAM.
+ *
+ *      0000-1FFF   RAM enable, 0x0A enables
+ *      2000-2FFF   ROM bank, low 8 bits
+ *      3000-3FFF   ROM bank, bit 8
+ *      4000-5FFF   RAM bank 0-F. On rumble carts bit 3 drives the motor
+ *                  instead and only 8 banks are available
+ *
+ *  Unlike earlier controllers, bank 0 can be mapped at 4000-7FFF.
+ */
+use super::{load_ram, ram_bank_number, ram_index, rom_byte, Cartridge, ROM_BANK_SIZE};
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    fn bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => usize::from(self.rom_bank),
+        }
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        rom_byte(&self.rom, self.bank(addr), addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = val == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | u16::from(val),
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (u16::from(val & 0x01) << 8)
+            }
+            0x4000..=0x5FFF if self.has_rumble => {
+                self.rumble = (val & 0x08) != 0;
+                self.ram_bank = val & 0x07;
+            }
+            0x4000..=0x5FFF => self.ram_bank = val & 0x0F,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+            Some(i) => self.ram[i],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_index(&self.ram, usize::from(self.ram_bank), addr) {
+            Some(i) => {
+                self.ram[i] = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        (self.bank(addr) % banks) as u16
+    }
+
+    fn ram_bank(&self) -> u16 {
+        ram_bank_number(&self.ram, usize::from(self.ram_bank))
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::numbered_rom;
+
+    #[test]
+    fn test_rom_banking() {
+        let mut m = Mbc5::new(numbered_rom(512), Vec::new(), false);
+        assert_eq!(m.read_rom(0x4000), 1);
+        m.write_rom(0x2000, 0x00);
+        assert_eq!(m.read_rom(0x4000), 0); // Bank 0 is selectable
+        m.write_rom(0x2000, 0x23);
+        m.write_rom(0x3000, 0x01);
+        assert_eq!((m.read_rom(0x4000), m.read_rom(0x4001)), (0x23, 0x01));
+        assert_eq!(m.rom_bank(0x4000), 0x123);
+        m.write_rom(0x2000, 0xFF); // Bit 8 is kept
+        assert_eq!(m.rom_bank(0x4000), 0x1FF);
+        assert_eq!(m.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut m = Mbc5::new(numbered_rom(2), vec![0; 0x20000], false);
+        m.write_rom(0x0000, 0x1A); // The whole byte must be 0x0A
+        m.write_ram(0xA000, 0x11);
+        assert_eq!(m.read_ram(0xA000), 0xFF);
+        m.write_rom(0x0000, 0x0A);
+        m.write_rom(0x4000, 0x0F);
+        m.write_ram(0xA000, 0x22);
+        assert_eq!(m.ram[0x1E000], 0x22);
+        m.write_rom(0x4000, 0x00);
+        assert_eq!(m.read_ram(0xA000), 0x00);
+    }
+
+    #[test]
+    fn test_rumble() {
+        let mut m = Mbc5::new(numbered_rom(2), vec![0; 0x20000], true);
+        m.write_rom(0x0000, 0x0A);
+        m.write_rom(0x4000, 0x0B);
+        assert!(m.rumble());
+        m.write_ram(0xA000, 0x33);
+        assert_eq!(m.ram[0x6000], 0x33); // Bit 3 is not a bank bit
+        m.write_rom(0x4000, 0x03);
+        assert!(!m.rumble());
+        assert!(!Mbc5::new(numbered_rom(2), Vec::new(), false).rumble());
+    }
+}
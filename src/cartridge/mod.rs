@@ -0,0 +1,439 @@
+/*
+ *  Cartridge hardware: the ROM, optional external RAM and the memory
+ *  bank controller (MBC) that maps them into 0000-7FFF and A000-BFFF.
+ */
+mod header;
+mod huc1;
+mod huc3;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+pub use self::header::{CgbSupport, Mapper, ROM};
+pub use self::huc1::Huc1;
+pub use self::huc3::Huc3;
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::rtc::RtcClock;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/*
+ *  The Nintendo logo at 0104-0133 of every licensed cartridge header.
+ */
+#[rustfmt::skip]
+pub const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+pub trait Cartridge {
+    /*
+     *  Read from 0000-7FFF.
+     */
+    fn read_rom(&self, addr: u16) -> u8;
+
+    /*
+     *  Write to 0000-7FFF. The ROM itself is read only, these writes
+     *  program the controller's registers.
+     */
+    fn write_rom(&mut self, addr: u16, val: u8);
+
+    /*
+     *  Read from A000-BFFF. Returns the open bus value 0xFF when RAM is
+     *  absent or disabled.
+     */
+    fn read_ram(&self, addr: u16) -> u8;
+
+    /*
+     *  Write to A000-BFFF. Returns whether the write was stored in RAM or
+     *  a clock register, i.e. changed what save_data() returns.
+     */
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool;
+
+    /*
+     *  ROM bank currently mapped at addr (0000-7FFF).
+     */
+    fn rom_bank(&self, addr: u16) -> u16;
+
+    /*
+     *  RAM bank currently mapped at A000-BFFF, wrapped at the size of the
+     *  RAM. MBC3 reports the clock register selected instead while one is
+     *  mapped.
+     */
+    fn ram_bank(&self) -> u16 {
+        0
+    }
+
+    /*
+     *  Let t_cycles of emulated time pass, counted at normal speed, for
+     *  controllers with a clock.
+     */
+    fn tick(&mut self, _t_cycles: u32) {}
+
+    fn set_rtc_clock(&mut self, _clock: RtcClock) {}
+
+    /*
+     *  Whether a rumble motor is switched on.
+     */
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /*
+     *  Whether an infrared LED is lit, on HuC carts.
+     */
+    fn infrared(&self) -> bool {
+        false
+    }
+
+    /*
+     *  Battery backed state in the .sav layout shared with other
+     *  emulators: the external RAM, followed by any clock data.
+     */
+    fn save_data(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_save(&mut self, _data: &[u8]) {}
+}
+
+/*
+ *  32K cartridge without a controller, optionally with 8K of RAM.
+ */
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> RomOnly {
+        RomOnly { rom, ram }
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.rom.get(addr as usize).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _val: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram
+            .get((addr - 0xA000) as usize)
+            .copied()
+            .unwrap_or(0xFF)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        match self.ram.get_mut((addr - 0xA000) as usize) {
+            Some(b) => {
+                *b = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => 1,
+            _ => 0,
+        }
+    }
+}
+
+/*
+ *  Reasons a ROM image can't be loaded.
+ */
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    UnsupportedMapper(u8), // Cartridge type byte
+    BadHeader(&'static str),
+    WrongSize { expected: usize, actual: usize },
+    Archive(String),               // Unreadable zip file
+    NoRomInArchive,                // No entry with a ROM extension or the requested name
+    AmbiguousArchive(Vec<String>), // Several ROMs and none was named
+    BadPatch(&'static str),
+    // CRC32 mismatch: which of source, target or patch, expected, actual
+    PatchCrc(&'static str, u32, u32),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "unable to read ROM: {}", e),
+            LoadError::UnsupportedMapper(t) => write!(f, "unsupported cartridge type ${:02X}", t),
+            LoadError::BadHeader(reason) => write!(f, "bad cartridge header: {}", reason),
+            LoadError::WrongSize { expected, actual } => write!(
+                f,
+                "ROM is {} bytes but the header says {}",
+                actual, expected
+            ),
+            LoadError::Archive(e) => write!(f, "bad archive: {}", e),
+            LoadError::NoRomInArchive => write!(f, "no matching ROM in archive"),
+            LoadError::AmbiguousArchive(names) => {
+                write!(f, "archive holds several ROMs: {}", names.join(", "))
+            }
+            LoadError::BadPatch(reason) => write!(f, "bad patch: {}", reason),
+            LoadError::PatchCrc(what, expected, actual) => write!(
+                f,
+                "patch {} CRC32 is {:08X}, expected {:08X}",
+                what, actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+/*
+ *  Check that a ROM image is one we can run: a header the boot ROM
+ *  would accept, a supported controller and a size matching the header.
+ *  The logo and global checksum are not checked, hardware ignores the
+ *  latter and homebrew often gets both wrong.
+ */
+pub fn validate(rom: &[u8], header: &ROM) -> Result<(), LoadError> {
+    if rom.len() < 0x150 {
+        return Err(LoadError::BadHeader("image too short to hold a header"));
+    }
+    if !header.header_checksum_ok {
+        return Err(LoadError::BadHeader("header checksum mismatch"));
+    }
+    if header.rom_bytes() == 0 {
+        return Err(LoadError::BadHeader("invalid ROM size code"));
+    }
+    if header.mapper().is_none() {
+        return Err(LoadError::UnsupportedMapper(header.cart_type));
+    }
+    if rom.len() != header.rom_bytes() {
+        return Err(LoadError::WrongSize {
+            expected: header.rom_bytes(),
+            actual: rom.len(),
+        });
+    }
+    Ok(())
+}
+
+/*
+ *  Build the cartridge for a ROM image as described by its header.
+ *  Unsupported controllers fall back to a plain ROM mapping.
+ */
+pub fn load(rom: Vec<u8>, header: &ROM) -> Box<dyn Cartridge> {
+    let ram = vec![0; header.ram_bytes()];
+    match header.mapper() {
+        Some(Mapper::Mbc1) => Box::new(Mbc1::new(rom, ram)),
+        Some(Mapper::Mbc2) => Box::new(Mbc2::new(rom)),
+        Some(Mapper::Mbc3) => Box::new(Mbc3::new(rom, ram, header.has_rtc())),
+        Some(Mapper::Mbc5) => Box::new(Mbc5::new(rom, ram, header.has_rumble())),
+        Some(Mapper::Huc1) => Box::new(Huc1::new(rom, ram)),
+        Some(Mapper::Huc3) => Box::new(Huc3::new(rom, ram)),
+        Some(Mapper::RomOnly) | None => Box::new(RomOnly::new(rom, ram)),
+    }
+}
+
+/*
+ *  External RAM size in bytes for the header's RAM size code.
+ */
+pub fn ram_size(code: u8) -> usize {
+    match code {
+        0x01 => 0x800, // Unofficial 2K, used by some homebrew
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+/*
+ *  Restore RAM from a save. A short save only fills the start of RAM.
+ */
+fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+/*
+ *  Byte at addr within a 16K ROM bank. Bank numbers wrap at the size of
+ *  the ROM, as the unused upper bank lines are not connected.
+ */
+fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    let i = (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+    rom.get(i).copied().unwrap_or(0xFF)
+}
+
+/*
+ *  Index into external RAM for addr (A000-BFFF) within an 8K bank,
+ *  wrapping at the size of the RAM. None if there is no RAM.
+ */
+fn ram_index(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    Some((bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1))) % ram.len())
+}
+
+/*
+ *  The bank ram_index() lands in, for Cartridge::ram_bank().
+ */
+fn ram_bank_number(ram: &[u8], bank: usize) -> u16 {
+    (bank % (ram.len() / RAM_BANK_SIZE).max(1)) as u16
+}
+
+/*
+ *  ROM where the first two bytes of every bank hold the bank number,
+ *  low byte first.
+ */
+#[cfg(test)]
+pub(crate) fn numbered_rom(banks: usize) -> Vec<u8> {
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for b in 0..banks {
+        rom[b * ROM_BANK_SIZE] = b as u8;
+        rom[b * ROM_BANK_SIZE + 1] = (b >> 8) as u8;
+    }
+    rom
+}
+
+/*
+ *  32K image without RAM whose header has the logo, the given title,
+ *  CGB flag and cartridge type, and correct checksums.
+ */
+#[cfg(test)]
+pub(crate) fn rom_image(title: &[u8], cgb: u8, cart_type: u8) -> Vec<u8> {
+    let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+    rom[0x104..0x134].copy_from_slice(&LOGO);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x143] = cgb;
+    rom[0x144..0x146].copy_from_slice(b"01");
+    rom[0x146] = 0x03;
+    rom[0x147] = cart_type;
+    rom[0x14A] = 0x01;
+    rom[0x14B] = 0x33;
+    rom[0x14C] = 0x02;
+    rom[0x14D] = (0x134..=0x14C).fold(0u8, |x, i| x.wrapping_sub(rom[i]).wrapping_sub(1));
+    let sum = rom.iter().fold(0u16, |x, &v| x.wrapping_add(u16::from(v)));
+    rom[0x14E] = (sum >> 8) as u8;
+    rom[0x14F] = sum as u8;
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram_size() {
+        assert_eq!(ram_size(0x00), 0);
+        assert_eq!(ram_size(0x02), 0x2000);
+        assert_eq!(ram_size(0x03), 0x8000);
+        assert_eq!(ram_size(0x05), 0x10000);
+    }
+
+    #[test]
+    fn test_rom_only() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000] = 0x12;
+        let header = ROM::parse(&rom);
+        let mut c = load(rom, &header);
+        c.write_rom(0x2000, 0x02);
+        assert_eq!(c.read_rom(0x4000), 0x12);
+        assert_eq!(c.rom_bank(0x4000), 1);
+        assert_eq!(c.read_ram(0xA000), 0xFF);
+    }
+
+    fn image(cart_type: u8) -> Vec<u8> {
+        rom_image(b"TEST", 0x00, cart_type)
+    }
+
+    fn check(rom: &[u8]) -> Result<(), LoadError> {
+        validate(rom, &ROM::parse(rom))
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(check(&image(0x00)).is_ok());
+        match check(&image(0x22)) {
+            Err(LoadError::UnsupportedMapper(0x22)) => {}
+            r => panic!("{:?}", r),
+        }
+        let mut rom = image(0x00);
+        rom[0x134] = b'A';
+        match check(&rom) {
+            Err(LoadError::BadHeader(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        match check(&image(0x00)[..0x4000]) {
+            Err(LoadError::WrongSize {
+                expected: 0x8000,
+                actual: 0x4000,
+            }) => {}
+            r => panic!("{:?}", r),
+        }
+        match check(&[0; 0x100]) {
+            Err(LoadError::BadHeader(_)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_hudson_mappers() {
+        let rom = image(0xFF);
+        let header = ROM::parse(&rom);
+        assert!(check(&rom).is_ok());
+        assert_eq!(header.mapper(), Some(Mapper::Huc1));
+        let mut c = load(rom, &header);
+        c.write_rom(0x0000, 0x0E);
+        c.write_ram(0xA000, 0x01);
+        assert!(c.infrared());
+
+        let rom = image(0xFE);
+        let header = ROM::parse(&rom);
+        assert_eq!(header.mapper(), Some(Mapper::Huc3));
+        assert!(header.has_battery() && header.has_rtc());
+        assert_eq!(load(rom, &header).save_data().len(), 17); // Clock only
+    }
+
+    #[test]
+    fn test_rom_byte_wraps() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x4000] = 0x01;
+        assert_eq!(rom_byte(&rom, 1, 0x4000), 0x01);
+        assert_eq!(rom_byte(&rom, 5, 0x4000), 0x01);
+    }
+}
@@ -0,0 +1,467 @@
+/*
+ *  MBC3 real-time clock.
+ *
+ *  Registers (selected by writing 08-0C to 4000-5FFF):
+ *      08  Seconds 0-59
+ *      09  Minutes 0-59
+ *      0A  Hours 0-23
+ *      0B  Day counter, low 8 bits
+ *      0C  Bit 0: day counter bit 8, bit 6: halt, bit 7: day carry
+ *
+ *  The CPU reads a latched copy, updated by writing 00 then 01 to
+ *  6000-7FFF. Writes go to the running clock.
+ *
+ *  Saves append the common 48-byte footer: the running then latched
+ *  registers as 32-bit little endian words, followed by a 64-bit UNIX
+ *  timestamp. Older saves with a 32-bit timestamp (44 bytes) also load.
+ */
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/*
+ *  Emulated T-cycles (at normal speed) per RTC second.
+ */
+pub const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+/*
+ *  Time source for cartridge clocks. Emulated counts CPU cycles and is
+ *  deterministic; Host follows the wall clock, so time passes while the
+ *  emulator is paused or closed.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RtcClock {
+    #[default]
+    Emulated,
+    Host,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Time {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9 bits
+    halt: bool,
+    carry: bool,
+}
+
+impl Time {
+    fn get(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            _ => {
+                ((self.days >> 8) as u8) | (u8::from(self.halt) << 6) | (u8::from(self.carry) << 7)
+            }
+        }
+    }
+
+    fn set(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => self.seconds = val & 0x3F,
+            0x09 => self.minutes = val & 0x3F,
+            0x0A => self.hours = val & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | u16::from(val),
+            _ => {
+                self.days = (self.days & 0xFF) | (u16::from(val & 0x01) << 8);
+                self.halt = (val & 0x40) != 0;
+                self.carry = (val & 0x80) != 0;
+            }
+        }
+    }
+
+    fn valid(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    /*
+     *  Advance by one second. Out of range values count up to the limit
+     *  of their bits and wrap to 0 without carrying, as on hardware.
+     */
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.add_days(1);
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let days = u64::from(self.days) + days;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn advance(&mut self, mut secs: u64) {
+        while secs > 0 && !self.valid() {
+            self.tick();
+            secs -= 1;
+        }
+        let total = u64::from(self.seconds)
+            + 60 * u64::from(self.minutes)
+            + 3600 * u64::from(self.hours)
+            + secs;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.add_days(total / 86400);
+    }
+}
+
+/*
+ *  Elapsed time for a cartridge clock, in whole seconds, from the
+ *  selected RtcClock. Shared by the MBC3 and HuC3 clocks so both count
+ *  time the same way.
+ */
+pub struct TimeSource {
+    clock: RtcClock,
+    cycles: u32,        // T-cycles into the current second
+    synced: SystemTime, // Host time the clock was last brought up to date
+}
+
+impl Default for TimeSource {
+    fn default() -> TimeSource {
+        TimeSource {
+            clock: RtcClock::default(),
+            cycles: 0,
+            synced: SystemTime::now(),
+        }
+    }
+}
+
+impl TimeSource {
+    /*
+     *  Let t_cycles of emulated time pass, returning the seconds
+     *  completed. Always 0 when following the host clock.
+     */
+    pub fn tick(&mut self, t_cycles: u32) -> u32 {
+        if self.clock != RtcClock::Emulated {
+            return 0;
+        }
+        self.cycles += t_cycles;
+        let secs = self.cycles / CYCLES_PER_SECOND;
+        self.cycles %= CYCLES_PER_SECOND;
+        secs
+    }
+
+    /*
+     *  Seconds of host time since the last sync, keeping the fraction
+     *  of a second for the next one. Always 0 for emulated time.
+     */
+    pub fn sync(&mut self) -> u64 {
+        if self.clock != RtcClock::Host {
+            return 0;
+        }
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.synced).unwrap_or_default();
+        self.synced = now - (elapsed - Duration::from_secs(elapsed.as_secs()));
+        elapsed.as_secs()
+    }
+
+    /*
+     *  Switch time source, returning the seconds that passed on the old
+     *  one since it was last synced.
+     */
+    pub fn set_clock(&mut self, clock: RtcClock) -> u64 {
+        let secs = self.sync();
+        self.clock = clock;
+        self.synced = SystemTime::now();
+        secs
+    }
+
+    /*
+     *  Start the current second over, as when the clock is set.
+     */
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+    }
+
+    /*
+     *  Pick up from a save written at the given UNIX time, returning the
+     *  seconds it spent on disk when following the host clock.
+     */
+    pub fn restore(&mut self, saved: u64) -> u64 {
+        self.cycles = 0;
+        self.synced = UNIX_EPOCH + Duration::from_secs(saved);
+        let secs = self.sync();
+        self.synced = SystemTime::now();
+        secs
+    }
+
+    /*
+     *  Current UNIX time, for save footers.
+     */
+    pub fn timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+}
+
+pub struct Rtc {
+    time: Time,
+    latched: Time,
+    latch: u8, // Last value written to 6000-7FFF
+    source: TimeSource,
+}
+
+impl Default for Rtc {
+    fn default() -> Rtc {
+        Rtc {
+            time: Time::default(),
+            latched: Time::default(),
+            latch: 0xFF,
+            source: TimeSource::default(),
+        }
+    }
+}
+
+impl Rtc {
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        let secs = self.source.set_clock(clock);
+        self.advance(secs);
+    }
+
+    /*
+     *  Bring the clock up to date with the host time.
+     */
+    fn sync(&mut self) {
+        let secs = self.source.sync();
+        self.advance(secs);
+    }
+
+    fn advance(&mut self, secs: u64) {
+        if !self.time.halt {
+            self.time.advance(secs);
+        }
+    }
+
+    /*
+     *  Let t_cycles of emulated time pass. Ignored when following the host
+     *  clock.
+     */
+    pub fn tick(&mut self, t_cycles: u32) {
+        if self.time.halt {
+            return;
+        }
+        for _ in 0..self.source.tick(t_cycles) {
+            self.time.tick();
+        }
+    }
+
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch == 0x00 && val == 0x01 {
+            self.sync();
+            self.latched = self.time;
+        }
+        self.latch = val;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.get(reg)
+    }
+
+    pub fn save(&mut self) -> Vec<u8> {
+        self.sync();
+        let mut data = Vec::with_capacity(48);
+        for t in [self.time, self.latched].iter() {
+            for reg in 0x08..=0x0C {
+                data.extend_from_slice(&u32::from(t.get(reg)).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&TimeSource::timestamp().to_le_bytes());
+        data
+    }
+
+    /*
+     *  Restore from a save footer. When following the host clock, the
+     *  time the save spent on disk is added on.
+     */
+    pub fn load(&mut self, data: &[u8]) {
+        if data.len() < 44 {
+            return;
+        }
+        for i in 0..5 {
+            self.time.set(0x08 + i as u8, data[i * 4]);
+            self.latched.set(0x08 + i as u8, data[20 + i * 4]);
+        }
+        let saved = match data.get(40..48) {
+            Some(ts) => u64::from_le_bytes(ts.try_into().unwrap()),
+            None => u64::from(u32::from_le_bytes(data[40..44].try_into().unwrap())),
+        };
+        let secs = self.source.restore(saved);
+        self.advance(secs);
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.sync();
+        if reg == 0x08 {
+            self.source.reset();
+        }
+        self.time.set(reg, val);
+        self.latched.set(reg, val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn test_counts_emulated_cycles() {
+        let mut rtc = Rtc::default();
+        rtc.tick(CYCLES_PER_SECOND - 4);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.tick(4);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 1);
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut rtc = Rtc::default();
+        rtc.tick(CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(0x08), 0); // Not latched yet
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 0); // Needs a 0 -> 1 transition
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 1);
+        rtc.tick(CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(0x08), 1);
+    }
+
+    #[test]
+    fn test_rollover() {
+        let mut rtc = Rtc::default();
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xFF);
+        rtc.tick(CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 0x00);
+        assert_eq!(rtc.read(0x0C), 0x01);
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let mut rtc = Rtc::default();
+        rtc.write(0x0C, 0x01);
+        rtc.write(0x0B, 0xFF);
+        rtc.time.advance(86400);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0B), 0x00);
+        assert_eq!(rtc.read(0x0C), 0x80); // Carry stays set until written
+        rtc.write(0x0C, 0x00);
+        assert_eq!(rtc.read(0x0C), 0x00);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut rtc = Rtc::default();
+        rtc.write(0x0C, 0x40);
+        rtc.tick(CYCLES_PER_SECOND * 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x0C), 0x40);
+        rtc.write(0x0C, 0x00);
+        rtc.tick(CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 1);
+    }
+
+    #[test]
+    fn test_host_clock_ignores_cycles() {
+        let mut rtc = Rtc::default();
+        rtc.set_clock(RtcClock::Host);
+        rtc.tick(CYCLES_PER_SECOND * 10);
+        latch(&mut rtc);
+        assert!(rtc.read(0x08) < 10);
+    }
+
+    #[test]
+    fn test_footer_round_trip() {
+        let mut rtc = Rtc::default();
+        rtc.write(0x0A, 13);
+        rtc.write(0x0C, 0x81);
+        latch(&mut rtc);
+        rtc.tick(CYCLES_PER_SECOND);
+        let data = rtc.save();
+        assert_eq!(data.len(), 48);
+        assert_eq!(&data[8..12], &[13, 0, 0, 0]);
+        assert_eq!(data[16], 0x81);
+        assert_eq!(data[0], 1); // Running
+        assert_eq!(data[20], 0); // Latched
+
+        let mut loaded = Rtc::default();
+        loaded.load(&data);
+        assert_eq!(loaded.time, rtc.time);
+        assert_eq!(loaded.latched, rtc.latched);
+    }
+
+    #[test]
+    fn test_footer_host_catch_up() {
+        let mut rtc = Rtc::default();
+        let mut data = rtc.save();
+        let then = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 3600;
+        data.truncate(40);
+        data.extend_from_slice(&(then as u32).to_le_bytes()); // 44-byte footer
+        rtc.set_clock(RtcClock::Host);
+        rtc.load(&data);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0A), 1);
+
+        let mut emulated = Rtc::default();
+        emulated.load(&data);
+        latch(&mut emulated);
+        assert_eq!(emulated.read(0x0A), 0);
+    }
+
+    #[test]
+    fn test_invalid_values_wrap() {
+        let mut t = Time::default();
+        t.set(0x08, 0x3F);
+        t.tick();
+        assert_eq!(t.seconds, 0);
+        assert_eq!(t.minutes, 0); // No carry out of an invalid value
+        t.set(0x08, 61);
+        t.advance(3 + 60);
+        assert_eq!(t.seconds, 0);
+        assert_eq!(t.minutes, 1);
+    }
+
+    #[test]
+    fn test_advance_long() {
+        let mut t = Time::default();
+        t.advance(3 * 86400 + 2 * 3600 + 5);
+        assert_eq!((t.days, t.hours, t.minutes, t.seconds), (3, 2, 0, 5));
+    }
+}
@@ -1,66 +1,173 @@
 #![allow(dead_code)]
 
-use super::mmu::MMUnit;
+use super::bus::Bus;
+use super::decode::{self, AccOp, AluOp, Cond, Instr, R16Mem, R16Stk, RotOp, R16, R8};
+use super::mmu::{Interrupt, MMUnit};
 use super::register::Flag;
 use super::register::Flag::{C, H, N, Z};
 use super::register::Register;
+use super::state::CpuState;
 use std::fmt;
 
+#[allow(clippy::upper_case_acronyms)]
+/*
+ *  SM83 core. Generic over the Bus it runs against; MMUnit is the full
+ *  system, other buses allow testing instructions in isolation.
+ */
 #[derive(Default)]
-pub struct CPU {
+pub struct CPU<B: Bus = MMUnit> {
     reg: Register,
-    pub mem: MMUnit, //TODO not sure it needs to be public.
+    pub mem: B,                    //TODO not sure it needs to be public.
+    ime: bool,                     // Interrupt Master Enable, toggled by DI/EI/RETI
+    ei_delay: u8,                  // EI takes effect after the following instruction
+    halted: bool,                  // HALT, waiting for an enabled interrupt
+    halt_bug: bool,                // HALT with IME=0 and an interrupt pending, next fetch repeats
+    stopped: bool,                 // STOP, waiting for a joypad press
+    cycles: u64,                   // Total M-cycles executed
+    locked: Option<IllegalOpcode>, // Set once an illegal opcode hangs the CPU
 }
 
-impl fmt::Display for CPU {
+/*
+ *  An illegal opcode was executed. The CPU locks up and stops fetching
+ *  until it is reset.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IllegalOpcode {
+    pub opcode: u8,
+    pub pc: u16,   // Address the opcode was fetched from
+    pub bank: u16, // ROM bank mapped at pc
+}
+
+impl fmt::Display for IllegalOpcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "(PC: {}, SP: {}, A: {}, B: {}, C: {}, D: {}, E: {}, F: {}, H: {}, L: {}, AF: {}, BC: {}, DE: {}, HL: {})",
-            self.reg.pc,
-            self.reg.sp,
-            self.reg.a,
-            self.reg.b,
-            self.reg.c,
-            self.reg.d,
-            self.reg.e,
-            self.reg.f,
-            self.reg.h,
-            self.reg.l,
-            self.reg.get_af(),
-            self.reg.get_bc(),
-            self.reg.get_de(),
-            self.reg.get_hl()
+            "illegal opcode ${:02X} at {:02X}:{:04X}",
+            self.opcode, self.bank, self.pc
         )
     }
 }
 
-impl CPU {
-    fn imm(&mut self) -> u8 {
-        let val = self.mem.get(self.reg.pc);
-        self.reg.pc += 1;
-        val
+impl std::error::Error for IllegalOpcode {}
+
+impl<B: Bus> fmt::Display for CPU<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.state())
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn new(mem: B) -> CPU<B> {
+        CPU {
+            reg: Register::default(),
+            mem,
+            ime: false,
+            ei_delay: 0,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            cycles: 0,
+            locked: None,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.reg.pc,
+            sp: self.reg.sp,
+            a: self.reg.a,
+            f: self.reg.f,
+            b: self.reg.b,
+            c: self.reg.c,
+            d: self.reg.d,
+            e: self.reg.e,
+            h: self.reg.h,
+            l: self.reg.l,
+            ime: self.ime,
+            halted: self.halted,
+            cycles: self.cycles,
+        }
+    }
+
+    /*
+     *  Overwrite the CPU state. A pending EI or HALT bug is discarded.
+     */
+    pub fn set_state(&mut self, s: &CpuState) {
+        self.reg.pc = s.pc;
+        self.reg.sp = s.sp;
+        self.reg.a = s.a;
+        self.reg.f = s.f & 0xF0;
+        self.reg.b = s.b;
+        self.reg.c = s.c;
+        self.reg.d = s.d;
+        self.reg.e = s.e;
+        self.reg.h = s.h;
+        self.reg.l = s.l;
+        self.ime = s.ime;
+        self.ei_delay = 0;
+        self.halted = s.halted;
+        self.halt_bug = false;
+        self.cycles = s.cycles;
+    }
+
+    /*
+     *  Every bus access and internal delay takes one M-cycle, during which
+     *  the rest of the system is advanced. Instruction timing falls out of
+     *  the accesses each instruction makes.
+     */
+    fn tick(&mut self) {
+        self.mem.tick();
+        self.cycles += 1;
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.mem.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.tick();
+        self.mem.write(addr, val);
     }
 
-    fn imm_hw(&mut self) -> u16 {
-        let val = self.mem.get_hw(self.reg.pc);
-        self.reg.pc += 2;
+    fn fetch(&mut self) -> u8 {
+        self.tick();
+        let op = self.mem.fetch(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
+        op
+    }
+
+    fn imm(&mut self) -> u8 {
+        let val = self.read(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
         val
     }
 
     fn stack_push(&mut self, val: u16) {
-        self.reg.sp -= 2;
-        self.mem.set_hw(self.reg.sp, val);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(self.reg.sp, (val >> 8) as u8);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(self.reg.sp, (val & 0xFF) as u8);
     }
 
     fn stack_pop(&mut self) -> u16 {
-        let val = self.mem.get_hw(self.reg.sp);
-        self.reg.sp += 2;
-        val
+        let lo = self.read(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let hi = self.read(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        u16::from(hi) << 8 | u16::from(lo)
     }
 
-    fn alu_add(&mut self, v: u8) {
-        let carry = if self.flag_c() { 1 } else { 0 };
+    fn alu_add(&mut self, v: u8, use_carry: bool) {
+        let carry = if use_carry && self.flag_c() { 1 } else { 0 };
         let a = self.reg.a;
         let res = a.wrapping_add(v).wrapping_add(carry);
         self.set_flag(Z, res == 0);
@@ -70,8 +177,8 @@ impl CPU {
         self.reg.a = res;
     }
 
-    fn alu_sub(&mut self, v: u8) {
-        let carry = if self.flag_c() { 1 } else { 0 };
+    fn alu_sub(&mut self, v: u8, use_carry: bool) {
+        let carry = if use_carry && self.flag_c() { 1 } else { 0 };
         let a = self.reg.a;
         let res = a.wrapping_sub(v).wrapping_sub(carry);
         self.set_flag(Z, res == 0);
@@ -100,7 +207,7 @@ impl CPU {
     }
 
     fn alu_xor(&mut self, v: u8) {
-        let res = self.reg.a | v;
+        let res = self.reg.a ^ v;
         self.set_flag(Z, res == 0);
         self.set_flag(N, false);
         self.set_flag(H, false);
@@ -110,22 +217,22 @@ impl CPU {
 
     fn alu_cp(&mut self, v: u8) {
         let res = self.reg.a;
-        self.alu_sub(v);
+        self.alu_sub(v, false);
         self.reg.a = res;
     }
 
     fn alu_dec(&mut self, v: u8) -> u8 {
-        let res = v.wrapping_add(1);
+        let res = v.wrapping_sub(1);
         self.set_flag(Z, res == 0);
         self.set_flag(N, true);
-        self.set_flag(H, (res & 0xF) == 0);
+        self.set_flag(H, (v & 0xF) == 0);
         res
     }
 
     fn alu_inc(&mut self, v: u8) -> u8 {
         let res = v.wrapping_add(1);
         self.set_flag(Z, res == 0);
-        self.set_flag(H, ((res & 0xF) + 1) > 0xF);
+        self.set_flag(H, ((v & 0xF) + 1) > 0xF);
         self.set_flag(N, false);
         res
     }
@@ -134,18 +241,23 @@ impl CPU {
         let a = self.reg.get_hl();
         let res = a.wrapping_add(v);
         self.set_flag(N, false);
-        self.set_flag(H, ((a & 0x07FF) + (v & 0x07FF)) > 0x07FF);
+        self.set_flag(H, ((a & 0x0FFF) + (v & 0x0FFF)) > 0x0FFF);
         self.set_flag(C, a > (0xFFFF - v));
         self.reg.set_hl(res);
     }
 
-    fn alu_add_hw_imm(&mut self, v: u16) -> u16 {
-        let a = self.imm() as i8 as i16 as u16; // TODO This is signed, test this conversion.
+    /*
+     *  SP plus a signed offset, for ADD SP,e8 and LD HL,SP+e8.
+     *  H and C come from the unsigned add of the low byte.
+     */
+    fn alu_add_sp(&mut self, e: i8) -> u16 {
+        let a = e as u16;
+        let v = self.reg.sp;
         self.set_flag(Z, false);
         self.set_flag(N, false);
         self.set_flag(H, ((a & 0x000F) + (v & 0x000F)) > 0x000F);
         self.set_flag(C, ((a & 0x00FF) + (v & 0x00FF)) > 0x00FF);
-        a.wrapping_add(v)
+        v.wrapping_add(a)
     }
 
     /*
@@ -162,7 +274,7 @@ impl CPU {
         self.set_flag(N, false);
         self.set_flag(H, false);
         self.set_flag(C, false);
-        (v >> 4) | (v << 4)
+        v.rotate_left(4)
     }
 
     /*
@@ -197,6 +309,7 @@ impl CPU {
         self.set_flag(Z, a == 0);
         self.set_flag(H, false);
         self.set_flag(C, adjust >= 0x60);
+        self.reg.a = a;
     }
 
     /*
@@ -209,6 +322,8 @@ impl CPU {
      */
     fn alu_cpl(&mut self) {
         self.reg.a = !(self.reg.a);
+        self.set_flag(N, true);
+        self.set_flag(H, true);
     }
 
     /*
@@ -248,9 +363,8 @@ impl CPU {
      *  C - Contains old bit 7 data
      */
     fn alu_rlc(&mut self, v: u8) -> u8 {
-        //TODO check this
         let c_res = (v & (1 << 7)) != 0;
-        let res = v << 1;
+        let res = v.rotate_left(1);
         self.set_flag(Z, res == 0);
         self.set_flag(N, false);
         self.set_flag(H, false);
@@ -264,11 +378,11 @@ impl CPU {
      *  Z - Set if res = 0
      *  N - Reset
      *  H - Reset
-     *  C - Contains old bit 0 data
+     *  C - Contains old bit 7 data
      */
     fn alu_rl(&mut self, v: u8) -> u8 {
         let c_res = (v & (1 << 7)) != 0;
-        let res = v << 1 + u8::from(self.flag_c());
+        let res = (v << 1) | u8::from(self.flag_c());
         self.set_flag(Z, res == 0);
         self.set_flag(N, false);
         self.set_flag(H, false);
@@ -401,8 +515,25 @@ impl CPU {
         !(1 << b) & r
     }
 
-    fn alu_jr(&mut self) {
-        // TODO writeme
+    /*
+     *  Service the highest priority pending interrupt, if IME is set.
+     *  IME is cleared, the IF bit acknowledged, PC pushed and execution
+     *  continues at the interrupt vector. Takes 5 M-cycles.
+     */
+    fn handle_interrupts(&mut self) -> bool {
+        let pending = self.mem.pending_interrupts();
+        if !self.ime || pending == 0 {
+            return false;
+        }
+        let i = Interrupt::from_bit(pending.trailing_zeros() as u8);
+        self.ime = false;
+        self.mem.acknowledge_interrupt(i);
+        self.tick();
+        self.tick();
+        self.stack_push(self.reg.pc);
+        self.tick();
+        self.reg.pc = i.vector();
+        true
     }
 
     pub fn set_flag(&mut self, flag: Flag, val: bool) {
@@ -436,550 +567,752 @@ impl CPU {
         (self.reg.f & 1 << 4) != 0
     }
 
-    #[allow(non_snake_case)]
-    pub fn ex(&mut self) {
-        let op = self.imm();
-        match op {
-            0x00 => {}
-            0x01 => {
-                let v = self.imm_hw();
-                self.reg.set_bc(v);
-            }
-            0x02 => {
-                self.mem.set(self.reg.get_bc(), self.reg.a);
-            }
-            0x03 => {
-                let v = self.reg.get_bc().wrapping_add(1);
-                self.reg.set_bc(v);
-            }
-            0x04 => {
-                self.reg.b = self.alu_inc(self.reg.b);
-            }
-            0x05 => {
-                self.reg.b = self.alu_dec(self.reg.b);
-            }
-            0x06 => {
-                self.reg.b = self.imm();
-            }
-            0x07 => {
-                self.reg.a = self.alu_rlc(self.reg.a);
-                self.set_flag(Z, false);
-            }
-            0x08 => {
-                let v = self.imm_hw();
-                self.mem.set_hw(v, self.reg.sp);
-            }
-            0x09 => {
-                self.alu_add_hw_hl(self.reg.get_bc());
-            }
-            0x0A => {
-                self.reg.a = self.mem.get(self.reg.get_bc());
-            }
-            0x0B => {
-                let v = self.reg.get_bc().wrapping_sub(1);
-                self.reg.set_bc(v);
-            }
-            0x0C => {
-                self.reg.c = self.alu_inc(self.reg.c);
-            }
-            0x0D => {
-                self.reg.c = self.alu_dec(self.reg.c);
-            }
-            0x0E => {
-                self.reg.c = self.imm();
-            }
-            0x0F => {
-                self.reg.a = self.alu_rrc(self.reg.a);
-                self.set_flag(Z, false);
-            }
-            0x10 => {
-                //TODO ...nothing? (STOP inst.)
-            }
-            0x11 => {
-                let v = self.imm_hw();
-                self.reg.set_de(v);
-            }
-            0x12 => {
-                self.mem.set(self.reg.get_de(), self.reg.a);
-            }
-            0x13 => {
-                self.reg.set_de(self.reg.get_de().wrapping_add(1));
-            }
-            0x14 => {
-                self.reg.d = self.alu_inc(self.reg.d);
-            }
-            0x15 => {
-                self.reg.d = self.alu_dec(self.reg.d);
-            }
-            0x16 => {
-                self.reg.d = self.imm();
+    fn get_r8(&mut self, r: R8) -> u8 {
+        match r {
+            R8::B => self.reg.b,
+            R8::C => self.reg.c,
+            R8::D => self.reg.d,
+            R8::E => self.reg.e,
+            R8::H => self.reg.h,
+            R8::L => self.reg.l,
+            R8::HLInd => self.read(self.reg.get_hl()),
+            R8::A => self.reg.a,
+        }
+    }
+
+    fn set_r8(&mut self, r: R8, v: u8) {
+        match r {
+            R8::B => self.reg.b = v,
+            R8::C => self.reg.c = v,
+            R8::D => self.reg.d = v,
+            R8::E => self.reg.e = v,
+            R8::H => self.reg.h = v,
+            R8::L => self.reg.l = v,
+            R8::HLInd => self.write(self.reg.get_hl(), v),
+            R8::A => self.reg.a = v,
+        }
+    }
+
+    fn get_r16(&self, r: R16) -> u16 {
+        match r {
+            R16::BC => self.reg.get_bc(),
+            R16::DE => self.reg.get_de(),
+            R16::HL => self.reg.get_hl(),
+            R16::SP => self.reg.sp,
+        }
+    }
+
+    fn set_r16(&mut self, r: R16, v: u16) {
+        match r {
+            R16::BC => self.reg.set_bc(v),
+            R16::DE => self.reg.set_de(v),
+            R16::HL => self.reg.set_hl(v),
+            R16::SP => self.reg.sp = v,
+        }
+    }
+
+    fn get_r16_stk(&self, r: R16Stk) -> u16 {
+        match r {
+            R16Stk::BC => self.reg.get_bc(),
+            R16Stk::DE => self.reg.get_de(),
+            R16Stk::HL => self.reg.get_hl(),
+            R16Stk::AF => self.reg.get_af(),
+        }
+    }
+
+    fn set_r16_stk(&mut self, r: R16Stk, v: u16) {
+        match r {
+            R16Stk::BC => self.reg.set_bc(v),
+            R16Stk::DE => self.reg.set_de(v),
+            R16Stk::HL => self.reg.set_hl(v),
+            R16Stk::AF => self.reg.set_af(v),
+        }
+    }
+
+    /*
+     *  Address for LD [r16], A / LD A, [r16]. HL+ and HL- adjust HL after
+     *  taking its value.
+     */
+    fn r16_mem_addr(&mut self, r: R16Mem) -> u16 {
+        match r {
+            R16Mem::BC => self.reg.get_bc(),
+            R16Mem::DE => self.reg.get_de(),
+            R16Mem::HLI => {
+                let hl = self.reg.get_hl();
+                self.reg.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            R16Mem::HLD => {
+                let hl = self.reg.get_hl();
+                self.reg.set_hl(hl.wrapping_sub(1));
+                hl
             }
-            0x17 => {
-                self.reg.a = self.alu_rl(self.reg.a);
+        }
+    }
+
+    fn cond(&self, c: Option<Cond>) -> bool {
+        match c {
+            None => true,
+            Some(Cond::NZ) => !self.flag_z(),
+            Some(Cond::Z) => self.flag_z(),
+            Some(Cond::NC) => !self.flag_c(),
+            Some(Cond::C) => self.flag_c(),
+        }
+    }
+
+    fn alu(&mut self, op: AluOp, v: u8) {
+        match op {
+            AluOp::Add => self.alu_add(v, false),
+            AluOp::Adc => self.alu_add(v, true),
+            AluOp::Sub => self.alu_sub(v, false),
+            AluOp::Sbc => self.alu_sub(v, true),
+            AluOp::And => self.alu_and(v),
+            AluOp::Xor => self.alu_xor(v),
+            AluOp::Or => self.alu_or(v),
+            AluOp::Cp => self.alu_cp(v),
+        }
+    }
+
+    fn rot(&mut self, op: RotOp, v: u8) -> u8 {
+        match op {
+            RotOp::Rlc => self.alu_rlc(v),
+            RotOp::Rrc => self.alu_rrc(v),
+            RotOp::Rl => self.alu_rl(v),
+            RotOp::Rr => self.alu_rr(v),
+            RotOp::Sla => self.alu_sla(v),
+            RotOp::Sra => self.alu_sra(v),
+            RotOp::Swap => self.alu_swap(v),
+            RotOp::Srl => self.alu_srl(v),
+        }
+    }
+
+    fn acc(&mut self, op: AccOp) {
+        match op {
+            AccOp::Rlca | AccOp::Rrca | AccOp::Rla | AccOp::Rra => {
+                let rot = match op {
+                    AccOp::Rlca => RotOp::Rlc,
+                    AccOp::Rrca => RotOp::Rrc,
+                    AccOp::Rla => RotOp::Rl,
+                    _ => RotOp::Rr,
+                };
+                self.reg.a = self.rot(rot, self.reg.a);
+                // Unlike the CB forms, the accumulator rotates always reset Z
                 self.set_flag(Z, false);
             }
-            0x18 => {
-                self.alu_jr();
-            }
-            0x19 => {
-                self.alu_add_hw_hl(self.reg.get_de());
-            }
-            0x1A => {
-                self.reg.a = self.mem.get(self.reg.get_de());
-            }
-            0x1B => {
-                self.reg.set_de(self.reg.get_de().wrapping_sub(1));
-            }
-            0x1C => {
-                self.reg.e = self.alu_inc(self.reg.e);
-            }
-            0x1D => {
-                self.reg.e = self.alu_dec(self.reg.e);
-            }
-            0x1E => {
-                self.reg.e = self.imm();
-            }
-            0x1F => {
-                //TODO RRA
-            }
-            0x20 => {
-                //TODO JR NC,r8
-            }
-            0x21 => {
-                let v = self.imm_hw();
-                self.reg.set_hl(v);
-            }
-            0x22 => {
-                //TODO LD (HL+), A
-            }
-            0x23 => {
-                self.reg.set_hl(self.reg.get_hl().wrapping_add(1));
-            }
-            0x24 => {
-                self.reg.h = self.alu_inc(self.reg.h);
-            }
-            0x25 => {
-                self.reg.h = self.alu_dec(self.reg.h);
-            }
-            0x26 => {
-                self.reg.h = self.imm();
-            }
-            0x27 => {
-                self.alu_daa();
-            }
-            0x28 => {
-                //TODO JR Z, r8
-            }
-            0x29 => {
-                self.alu_add_hw_hl(self.reg.get_hl());
-            }
-            0x2A => {
-                //TODO LD A, (HL+)
-            }
-            0x2B => {
-                self.reg.set_hl(self.reg.get_hl().wrapping_sub(1));
-            }
-            0x2C => {
-                self.reg.l = self.alu_inc(self.reg.l);
-            }
-            0x2D => {
-                self.reg.l = self.alu_dec(self.reg.l);
-            }
-            0x2E => {
-                self.reg.l = self.imm();
-            }
-            0x2F => {
-                self.alu_cpl();
-            }
-            0x30 => {
-                //TODO JR NC, r8
-            }
-            0x31 => {
-                let v = u16::from(self.imm());
-                self.reg.sp = v;
-            }
-            0x32 => {
-                //TODO LD (HL-) , A
-            }
-            0x33 => {
-                self.reg.sp.wrapping_add(1);
-            }
-            0x34 => {
-                let v = self.reg.get_hl().wrapping_add(1);
-                self.reg.set_hl(v);
-            }
-            0x35 => {
-                let v = self.reg.get_hl().wrapping_sub(1);
+            AccOp::Daa => self.alu_daa(),
+            AccOp::Cpl => self.alu_cpl(),
+            AccOp::Scf => self.alu_scf(),
+            AccOp::Ccf => self.alu_ccf(),
+        }
+    }
+
+    /*
+     *  Execute a decoded instruction. Memory operands and internal delays
+     *  tick the bus as they happen.
+     */
+    fn execute(&mut self, instr: Instr) {
+        match instr {
+            Instr::Nop => {}
+            Instr::Stop => {
+                self.mem.reset_divider();
+                // The pause of about 2050 M-cycles while the speed switches
+                // isn't modelled, the CPU carries straight on
+                if !self.mem.switch_speed() {
+                    self.stopped = true;
+                }
+            }
+            Instr::Halt => {
+                if !self.ime && self.mem.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
+            Instr::Di => {
+                self.ime = false;
+                self.ei_delay = 0;
+            }
+            Instr::Ei => self.ei_delay = 2,
+            Instr::Ld(d, s) => {
+                let v = self.get_r8(s);
+                self.set_r8(d, v);
+            }
+            Instr::LdImm(r, n) => self.set_r8(r, n),
+            Instr::LdR16Imm(r, n) => self.set_r16(r, n),
+            Instr::LdMemA(r) => {
+                let addr = self.r16_mem_addr(r);
+                self.write(addr, self.reg.a);
+            }
+            Instr::LdAMem(r) => {
+                let addr = self.r16_mem_addr(r);
+                self.reg.a = self.read(addr);
+            }
+            Instr::LdAbsA(n) => self.write(n, self.reg.a),
+            Instr::LdAAbs(n) => self.reg.a = self.read(n),
+            Instr::LdhA8A(n) => self.write(0xFF00 | u16::from(n), self.reg.a),
+            Instr::LdhAA8(n) => self.reg.a = self.read(0xFF00 | u16::from(n)),
+            Instr::LdhCA => self.write(0xFF00 | u16::from(self.reg.c), self.reg.a),
+            Instr::LdhAC => self.reg.a = self.read(0xFF00 | u16::from(self.reg.c)),
+            Instr::LdAbsSp(n) => {
+                self.write(n, (self.reg.sp & 0xFF) as u8);
+                self.write(n.wrapping_add(1), (self.reg.sp >> 8) as u8);
+            }
+            Instr::LdSpHl => {
+                self.tick();
+                self.reg.sp = self.reg.get_hl();
+            }
+            Instr::LdHlSp(e) => {
+                let v = self.alu_add_sp(e);
+                self.tick();
                 self.reg.set_hl(v);
             }
-            0x36 => {
-                let v = self.imm();
-                self.reg.set_hl(u16::from(v));
-            }
-            0x37 => {
-                self.alu_scf();
-            }
-            0x38 => {
-                //TODO JR C, r8
-            }
-            0x39 => {
-                self.alu_add_hw_hl(self.reg.sp);
-            }
-            0x3A => {
-                // TODO LD A, (HL-)
-            }
-            0x3B => {
-                let v = self.reg.sp.wrapping_sub(1);
-                self.reg.sp = v;
-            }
-            0x3C => {
-                self.reg.a = self.alu_inc(self.reg.a);
-            }
-            0x3D => {
-                self.reg.a = self.alu_dec(self.reg.a);
-            }
-            0x3E => {
-                let v = self.imm();
-                self.reg.a = v;
-            }
-            0x3F => {
-                self.alu_ccf();
-            }
-            0x40 => {
-                self.reg.b = self.reg.b;
-            }
-            0x41 => {
-                self.reg.b = self.reg.c;
-            }
-            0x42 => {
-                self.reg.b = self.reg.d;
-            }
-            0x43 => {
-                self.reg.b = self.reg.e;
-            }
-            0x44 => {
-                self.reg.b = self.reg.h;
-            }
-            0x45 => {
-                self.reg.b = self.reg.l;
-            }
-            0x46 => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.b = v;
-            }
-            0x47 => {
-                self.reg.b = self.reg.a;
-            }
-            0x48 => {
-                self.reg.c = self.reg.b;
-            }
-            0x49 => {
-                self.reg.c = self.reg.c;
-            }
-            0x4A => {
-                self.reg.c = self.reg.d;
-            }
-            0x4B => {
-                self.reg.c = self.reg.e;
-            }
-            0x4C => {
-                self.reg.c = self.reg.h;
-            }
-            0x4D => {
-                self.reg.c = self.reg.l;
-            }
-            0x4E => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.c = v;
-            }
-            0x4F => {
-                self.reg.c = self.reg.a;
-            }
-            0x50 => {
-                self.reg.d = self.reg.b;
-            }
-            0x51 => {
-                self.reg.d = self.reg.c;
-            }
-            0x52 => {
-                self.reg.d = self.reg.d;
-            }
-            0x53 => {
-                self.reg.d = self.reg.e;
-            }
-            0x54 => {
-                self.reg.d = self.reg.h;
-            }
-            0x55 => {
-                self.reg.d = self.reg.l;
-            }
-            0x56 => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.d = v;
-            }
-            0x57 => {
-                self.reg.d = self.reg.a;
-            }
-            0x58 => {
-                self.reg.e = self.reg.b;
-            }
-            0x59 => {
-                self.reg.e = self.reg.c;
-            }
-            0x5A => {
-                self.reg.e = self.reg.d;
-            }
-            0x5B => {
-                self.reg.e = self.reg.e;
-            }
-            0x5C => {
-                self.reg.e = self.reg.h;
-            }
-            0x5D => {
-                self.reg.e = self.reg.l;
-            }
-            0x5E => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.e = v;
-            }
-            0x5F => {
-                self.reg.e = self.reg.a;
-            }
-            0x60 => {
-                self.reg.h = self.reg.b;
-            }
-            0x61 => {
-                self.reg.h = self.reg.c;
-            }
-            0x62 => {
-                self.reg.h = self.reg.d;
-            }
-            0x63 => {
-                self.reg.h = self.reg.e;
-            }
-            0x64 => {
-                self.reg.h = self.reg.h;
-            }
-            0x65 => {
-                self.reg.h = self.reg.l;
-            }
-            0x66 => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.h = v;
-            }
-            0x67 => {
-                self.reg.h = self.reg.a;
-            }
-            0x68 => {
-                self.reg.l = self.reg.b;
-            }
-            0x69 => {
-                self.reg.l = self.reg.c;
-            }
-            0x6A => {
-                self.reg.l = self.reg.d;
-            }
-            0x6B => {
-                self.reg.l = self.reg.e;
-            }
-            0x6C => {
-                self.reg.l = self.reg.h;
+            Instr::Inc(r) => {
+                let v = self.get_r8(r);
+                let v = self.alu_inc(v);
+                self.set_r8(r, v);
+            }
+            Instr::Dec(r) => {
+                let v = self.get_r8(r);
+                let v = self.alu_dec(v);
+                self.set_r8(r, v);
+            }
+            Instr::IncR16(r) => {
+                self.tick();
+                self.set_r16(r, self.get_r16(r).wrapping_add(1));
+            }
+            Instr::DecR16(r) => {
+                self.tick();
+                self.set_r16(r, self.get_r16(r).wrapping_sub(1));
+            }
+            Instr::AddHl(r) => {
+                self.tick();
+                self.alu_add_hw_hl(self.get_r16(r));
+            }
+            Instr::AddSp(e) => {
+                self.reg.sp = self.alu_add_sp(e);
+                self.tick();
+                self.tick();
+            }
+            Instr::Alu(op, r) => {
+                let v = self.get_r8(r);
+                self.alu(op, v);
+            }
+            Instr::AluImm(op, n) => self.alu(op, n),
+            Instr::Acc(op) => self.acc(op),
+            Instr::Rot(op, r) => {
+                let v = self.get_r8(r);
+                let v = self.rot(op, v);
+                self.set_r8(r, v);
+            }
+            Instr::Bit(b, r) => {
+                let v = self.get_r8(r);
+                self.alu_bit(b, v);
+            }
+            Instr::Res(b, r) => {
+                let v = self.get_r8(r);
+                self.set_r8(r, self.alu_reset(b, v));
+            }
+            Instr::Set(b, r) => {
+                let v = self.get_r8(r);
+                self.set_r8(r, self.alu_set(b, v));
+            }
+            Instr::Jr(c, e) => {
+                if self.cond(c) {
+                    self.tick();
+                    self.reg.pc = self.reg.pc.wrapping_add(e as u16);
+                }
+            }
+            Instr::Jp(c, n) => {
+                if self.cond(c) {
+                    self.tick();
+                    self.reg.pc = n;
+                }
+            }
+            Instr::JpHl => self.reg.pc = self.reg.get_hl(),
+            Instr::Call(c, n) => {
+                if self.cond(c) {
+                    self.tick();
+                    self.stack_push(self.reg.pc);
+                    self.reg.pc = n;
+                }
+            }
+            Instr::Ret(c) => {
+                if c.is_some() {
+                    // Evaluating the condition takes a cycle
+                    self.tick();
+                }
+                if self.cond(c) {
+                    self.reg.pc = self.stack_pop();
+                    self.tick();
+                }
+            }
+            Instr::Reti => {
+                self.reg.pc = self.stack_pop();
+                self.tick();
+                self.ime = true;
+            }
+            Instr::Rst(n) => {
+                self.tick();
+                self.stack_push(self.reg.pc);
+                self.reg.pc = u16::from(n);
+            }
+            Instr::Push(r) => {
+                self.tick();
+                self.stack_push(self.get_r16_stk(r));
+            }
+            Instr::Pop(r) => {
+                let v = self.stack_pop();
+                self.set_r16_stk(r, v);
+            }
+            Instr::Illegal(_) => {}
+        }
+    }
+
+    /*
+     *  The illegal opcode that locked the CPU, if any.
+     */
+    pub fn locked(&self) -> Option<IllegalOpcode> {
+        self.locked
+    }
+
+    /*
+     *  Run one step: an instruction, an interrupt dispatch, or a single
+     *  M-cycle of HALT/STOP/lock-up. Returns the M-cycles taken.
+     */
+    pub fn ex(&mut self) -> u32 {
+        let start = self.cycles;
+
+        if self.locked.is_some() {
+            // Hung: no fetches and no interrupts, time still passes
+            self.tick();
+            return 1;
+        }
+
+        if self.stopped {
+            if !self.mem.joypad_pressed() {
+                self.tick();
+                return 1;
             }
-            0x6D => {
-                self.reg.l = self.reg.l;
+            self.stopped = false;
+        }
+
+        if self.halted {
+            if self.mem.pending_interrupts() == 0 {
+                self.tick();
+                return 1;
             }
-            0x6E => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.l = v;
+            self.halted = false;
+        }
+
+        if self.handle_interrupts() {
+            return (self.cycles - start) as u32;
+        }
+
+        let pc = self.reg.pc;
+        let op = self.fetch();
+        if self.halt_bug {
+            // PC fails to increment past the byte after HALT
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
+        let instr = decode::decode(op, || self.imm());
+        if let Instr::Illegal(opcode) = instr {
+            self.locked = Some(IllegalOpcode {
+                opcode,
+                pc,
+                bank: self.mem.bank(pc),
+            });
+        }
+        self.execute(instr);
+
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+            if self.ei_delay == 0 {
+                self.ime = true;
             }
-            0x6F => {
-                self.reg.l = self.reg.a;
-            }
-            0x70 => {
-                self.mem.set(self.reg.get_hl(), self.reg.b);
-            }
-            0x71 => {
-                self.mem.set(self.reg.get_hl(), self.reg.c);
-            }
-            0x72 => {
-                self.mem.set(self.reg.get_hl(), self.reg.d);
-            }
-            0x73 => {
-                self.mem.set(self.reg.get_hl(), self.reg.e);
-            }
-            0x74 => {
-                self.mem.set(self.reg.get_hl(), self.reg.h);
-            }
-            0x75 => {
-                self.mem.set(self.reg.get_hl(), self.reg.l);
-            }
-            0x76 => {
-                // TODO: HALT
-            }
-            0x77 => {
-                self.mem.set(self.reg.get_hl(), self.reg.a);
-            }
-            0x78 => {
-                self.reg.a = self.reg.b;
-            }
-            0x79 => {
-                self.reg.a = self.reg.c;
-            }
-            0x7A => {
-                self.reg.a = self.reg.d;
-            }
-            0x7B => {
-                self.reg.a = self.reg.e;
-            }
-            0x7C => {
-                self.reg.a = self.reg.h;
-            }
-            0x7D => {
-                self.reg.a = self.reg.l;
-            }
-            0x7E => {
-                let v = self.mem.get(self.reg.get_hl());
-                self.reg.a = v;
-            }
-            0x7F => {
-                self.reg.a = self.reg.a;
-            }
-            0x80 => {}
-            0x81 => {}
-            0x82 => {}
-            0x83 => {}
-            0x84 => {}
-            0x85 => {}
-            0x86 => {}
-            0x87 => {}
-            0x88 => {}
-            0x89 => {}
-            0x8A => {}
-            0x8B => {}
-            0x8C => {}
-            0x8D => {}
-            0x8E => {}
-            0x8F => {}
-            0x90 => {}
-            0x91 => {}
-            0x92 => {}
-            0x93 => {}
-            0x94 => {}
-            0x95 => {}
-            0x96 => {}
-            0x97 => {}
-            0x98 => {}
-            0x99 => {}
-            0x9A => {}
-            0x9B => {}
-            0x9C => {}
-            0x9D => {}
-            0x9E => {}
-            0x9F => {}
-            0xA0 => {}
-            0xA1 => {}
-            0xA2 => {}
-            0xA3 => {}
-            0xA4 => {}
-            0xA5 => {}
-            0xA6 => {}
-            0xA7 => {}
-            0xA8 => {}
-            0xA9 => {}
-            0xAA => {}
-            0xAB => {}
-            0xAC => {}
-            0xAD => {}
-            0xAE => {}
-            0xAF => {}
-            0xB0 => {}
-            0xB1 => {}
-            0xB2 => {}
-            0xB3 => {}
-            0xB4 => {}
-            0xB5 => {}
-            0xB6 => {}
-            0xB7 => {}
-            0xB8 => {}
-            0xB9 => {}
-            0xBA => {}
-            0xBB => {}
-            0xBC => {}
-            0xBD => {}
-            0xBE => {}
-            0xBF => {}
-            0xC0 => {}
-            0xC1 => {}
-            0xC2 => {}
-            0xC3 => {}
-            0xC4 => {}
-            0xC5 => {}
-            0xC6 => {}
-            0xC7 => {}
-            0xC8 => {}
-            0xC9 => {}
-            0xCA => {}
-            0xCB => {}
-            0xCC => {}
-            0xCD => {}
-            0xCE => {}
-            0xCF => {}
-            0xD0 => {}
-            0xD1 => {}
-            0xD2 => {}
-            0xD3 => {}
-            0xD4 => {}
-            0xD5 => {}
-            0xD6 => {}
-            0xD7 => {}
-            0xD8 => {}
-            0xD9 => {}
-            0xDA => {}
-            0xDB => {}
-            0xDC => {}
-            0xDD => {}
-            0xDE => {}
-            0xDF => {}
-            0xE0 => {}
-            0xE1 => {}
-            0xE2 => {}
-            0xE3 => {}
-            0xE4 => {}
-            0xE5 => {}
-            0xE6 => {}
-            0xE7 => {}
-            0xE8 => {}
-            0xE9 => {}
-            0xEA => {}
-            0xEB => {}
-            0xEC => {}
-            0xED => {}
-            0xEE => {}
-            0xEF => {}
-            0xF0 => {}
-            0xF1 => {}
-            0xF2 => {}
-            0xF3 => {}
-            0xF4 => {}
-            0xF5 => {}
-            0xF6 => {}
-            0xF7 => {}
-            0xF8 => {}
-            0xF9 => {}
-            0xFA => {}
-            0xFB => {}
-            0xFC => {}
-            0xFD => {}
-            0xFE => {}
-            0xFF => {}
-            _ => println!("Dunno what I found"),
         }
+        (self.cycles - start) as u32
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bus::FlatRam;
+    use crate::joypad::Button;
+    use crate::mmu::Model;
+
+    fn load(cpu: &mut CPU, prog: &[u8]) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + prog.len()].copy_from_slice(prog);
+        cpu.mem.set_rom(rom);
+    }
+
+    #[test]
+    fn test_xor_a() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xAF]); // XOR A
+        a.reg.a = 0x5A;
+        a.ex();
+        assert_eq!(a.reg.a, 0);
+        assert!(a.flag_z());
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xC5, 0xD1]); // PUSH BC; POP DE
+        a.reg.set_bc(0x1234);
+        a.ex();
+        a.ex();
+        assert_eq!(a.reg.get_de(), 0x1234);
+        assert_eq!(a.reg.sp, 0xFFFE);
+    }
+
+    #[test]
+    fn test_pop_af_masks_flags() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xC5, 0xF1]); // PUSH BC; POP AF
+        a.reg.set_bc(0x12FF);
+        a.ex();
+        a.ex();
+        assert_eq!(a.reg.get_af(), 0x12F0);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xCD, 0x00, 0xC0]); // CALL 0xC000
+        a.mem.set(0xC000, 0xC9); // RET
+        a.ex();
+        assert_eq!(a.reg.pc, 0xC000);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x103);
+    }
+
+    #[test]
+    fn test_jp_cond() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xC2, 0x00, 0x02, 0xCA, 0x00, 0x03]); // JP NZ,0x0200; JP Z,0x0300
+        a.set_flag(Z, true);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x103);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x300);
+    }
+
+    #[test]
+    fn test_cp_imm() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xFE, 0x10]); // CP 0x10
+        a.reg.a = 0x10;
+        a.ex();
+        assert_eq!(a.reg.a, 0x10);
+        assert!(a.flag_z());
+        assert!(a.flag_n());
+    }
+
+    #[test]
+    fn test_ldh() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xE0, 0x80, 0xF0, 0x81]); // LDH (0x80),A; LDH A,(0x81)
+        a.reg.a = 0x42;
+        a.mem.set(0xFF81, 0x24);
+        a.ex();
+        assert_eq!(a.mem.get(0xFF80), 0x42);
+        a.ex();
+        assert_eq!(a.reg.a, 0x24);
+    }
+
+    #[test]
+    fn test_cb_rlc() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xCB, 0x00]); // RLC B
+        a.reg.b = 0x85;
+        a.ex();
+        assert_eq!(a.reg.b, 0x0B);
+        assert!(a.flag_c());
+    }
+
+    #[test]
+    fn test_cb_bit() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xCB, 0x7C, 0xCB, 0x47]); // BIT 7,H; BIT 0,A
+        a.reg.h = 0x80;
+        a.ex();
+        assert!(!a.flag_z());
+        a.ex();
+        assert!(a.flag_z());
+        assert!(a.flag_h());
+    }
+
+    #[test]
+    fn test_cb_hl_operand() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xCB, 0xC6, 0xCB, 0x36]); // SET 0,(HL); SWAP (HL)
+        a.reg.set_hl(0xC000);
+        a.mem.set(0xC000, 0x20);
+        a.ex();
+        assert_eq!(a.mem.get(0xC000), 0x21);
+        a.ex();
+        assert_eq!(a.mem.get(0xC000), 0x12);
+    }
+
+    #[test]
+    fn test_cycles_jr() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x20, 0x02, 0x20, 0xFC]); // JR NZ,+2; JR NZ,-4
+        a.set_flag(Z, true);
+        assert_eq!(a.ex(), 2);
+        assert_eq!(a.reg.pc, 0x102);
+        a.set_flag(Z, false);
+        assert_eq!(a.ex(), 3);
+        assert_eq!(a.reg.pc, 0x100);
+        assert_eq!(a.cycles(), 5);
+    }
+
+    #[test]
+    fn test_cycles_call_ret() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xDC, 0x00, 0xC0, 0xCD, 0x00, 0xC0]); // CALL C,0xC000; CALL 0xC000
+        a.mem.set(0xC000, 0xC8); // RET Z
+        a.mem.set(0xC001, 0xC0); // RET NZ
+        assert_eq!(a.ex(), 3);
+        assert_eq!(a.ex(), 6);
+        a.set_flag(Z, false);
+        assert_eq!(a.ex(), 2);
+        assert_eq!(a.ex(), 5);
+        assert_eq!(a.reg.pc, 0x106);
+    }
+
+    #[test]
+    fn test_cycles_cb() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xCB, 0x11, 0xCB, 0x46, 0xCB, 0x86]); // RL C; BIT 0,(HL); RES 0,(HL)
+        a.reg.set_hl(0xC000);
+        assert_eq!(a.ex(), 2);
+        assert_eq!(a.ex(), 3);
+        assert_eq!(a.ex(), 4);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x00]);
+        a.ime = true;
+        a.mem.set(0xFFFF, 0x1F);
+        a.mem.request_interrupt(Interrupt::Joypad);
+        a.mem.request_interrupt(Interrupt::Timer);
+        assert_eq!(a.ex(), 5);
+        assert_eq!(a.reg.pc, 0x50);
+        assert_eq!(a.mem.get_hw(a.reg.sp), 0x100);
+        assert_eq!(a.mem.get(0xFF0F) & 0x1F, 0x10);
+        assert!(!a.ime);
+    }
+
+    #[test]
+    fn test_interrupt_masked() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x00]);
+        a.ime = true;
+        a.mem.set(0xFFFF, 0x01);
+        a.mem.request_interrupt(Interrupt::Timer);
+        assert_eq!(a.ex(), 1);
+        assert_eq!(a.reg.pc, 0x101);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xFB, 0x00, 0x00]); // EI; NOP; NOP
+        a.mem.set(0xFFFF, 0x01);
+        a.mem.request_interrupt(Interrupt::VBlank);
+        a.ex();
+        a.ex();
+        assert_eq!(a.reg.pc, 0x102);
+        assert_eq!(a.ex(), 5);
+        assert_eq!(a.reg.pc, 0x40);
+    }
+
+    #[test]
+    fn test_reti() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0xD9]); // RETI
+        a.stack_push(0x1234);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x1234);
+        assert!(a.ime);
+    }
+
+    #[test]
+    fn test_halt_wakes_on_interrupt() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x76, 0x00]); // HALT; NOP
+        a.ime = true;
+        a.mem.set(0xFFFF, 0x04);
+        a.ex();
+        assert!(a.halted);
+        assert_eq!(a.ex(), 1);
+        assert_eq!(a.reg.pc, 0x101);
+        a.mem.request_interrupt(Interrupt::Timer);
+        assert_eq!(a.ex(), 5);
+        assert!(!a.halted);
+        assert_eq!(a.reg.pc, 0x50);
+        assert_eq!(a.mem.get_hw(a.reg.sp), 0x101);
+    }
+
+    #[test]
+    fn test_halt_ime_off_resumes() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x76, 0x3C]); // HALT; INC A
+        a.mem.set(0xFFFF, 0x04);
+        a.ex();
+        a.mem.request_interrupt(Interrupt::Timer);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x102);
+        assert_eq!(a.reg.a, 1);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x76, 0x3C]); // HALT; INC A
+        a.mem.set(0xFFFF, 0x04);
+        a.mem.request_interrupt(Interrupt::Timer);
+        a.ex();
+        assert!(!a.halted);
+        a.ex();
+        a.ex();
+        assert_eq!(a.reg.a, 2);
+        assert_eq!(a.reg.pc, 0x102);
+    }
+
+    #[test]
+    fn test_stop_exits_on_press() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x10, 0x00, 0x3C]); // STOP; INC A
+        a.ex();
+        assert!(a.stopped);
+        a.ex();
+        assert_eq!(a.reg.pc, 0x102);
+        a.mem.press(Button::A);
+        a.ex();
+        assert_eq!(a.reg.a, 1);
+    }
+
+    #[test]
+    fn test_stop_speed_switch() {
+        let mut a = CPU::new(MMUnit::new(Model::Cgb));
+        load(&mut a, &[0x10, 0x00]); // STOP
+        a.mem.set(0xFF4D, 0x01);
+        for _ in 0..0x100 {
+            a.mem.tick();
+        }
+        assert_ne!(a.mem.get(0xFF04), 0);
+        a.ex();
+        assert!(!a.stopped);
+        assert!(a.mem.double_speed());
+        assert_eq!(a.mem.get(0xFF4D), 0xFE);
+        assert_eq!(a.mem.get(0xFF04), 0); // Reset by STOP
+    }
+
+    #[test]
+    fn test_hl_operands() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x34, 0x22, 0x3A]); // INC (HL); LD (HL+),A; LD A,(HL-)
+        a.reg.set_hl(0xC000);
+        a.mem.set(0xC000, 0x0F);
+        assert_eq!(a.ex(), 3);
+        assert_eq!(a.mem.get(0xC000), 0x10);
+        assert!(a.flag_h());
+        a.reg.a = 0x55;
+        a.ex();
+        assert_eq!(a.mem.get(0xC000), 0x55);
+        assert_eq!(a.reg.get_hl(), 0xC001);
+        a.mem.set(0xC001, 0x66);
+        a.ex();
+        assert_eq!(a.reg.a, 0x66);
+        assert_eq!(a.reg.get_hl(), 0xC000);
+    }
+
+    #[test]
+    fn test_dec_daa() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x05, 0xC6, 0x19, 0x27]); // DEC B; ADD A,0x19; DAA
+        a.reg.b = 0x10;
+        a.reg.a = 0x28;
+        a.ex();
+        assert_eq!(a.reg.b, 0x0F);
+        assert!(a.flag_h() && a.flag_n());
+        a.ex();
+        a.ex();
+        assert_eq!(a.reg.a, 0x47);
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x00, 0xDD, 0x3C]); // NOP; illegal; INC A
+        a.ime = true;
+        a.mem.set(0xFFFF, 0x01);
+        a.ex();
+        assert!(a.locked().is_none());
+        a.ex();
+        let err = a.locked().unwrap();
+        assert_eq!(err.opcode, 0xDD);
+        assert_eq!(err.pc, 0x101);
+        a.mem.request_interrupt(Interrupt::VBlank);
+        assert_eq!(a.ex(), 1);
+        assert_eq!(a.reg.pc, 0x102);
+        assert_eq!(a.reg.a, 0);
+    }
+
+    #[test]
+    fn test_cycles_per_access() {
+        let mut a: CPU = Default::default();
+        // PUSH BC; POP BC; ADD SP,1; LD (0xC000),SP; INC BC; RST 0x08
+        load(
+            &mut a,
+            &[0xC5, 0xC1, 0xE8, 0x01, 0x08, 0x00, 0xC0, 0x03, 0xCF],
+        );
+        assert_eq!(a.ex(), 4);
+        assert_eq!(a.ex(), 3);
+        assert_eq!(a.ex(), 4);
+        assert_eq!(a.ex(), 5);
+        assert_eq!(a.ex(), 2);
+        assert_eq!(a.ex(), 4);
+    }
 
+    #[test]
+    fn test_timer_ticks_between_accesses() {
+        let mut a: CPU = Default::default();
+        // LDH A,(0x05); LD A,(0xFF05)
+        load(&mut a, &[0xF0, 0x05, 0xFA, 0x05, 0xFF]);
+        a.mem.set(0xFF07, 0x05); // TIMA increments every 4 M-cycles
+        a.ex();
+        assert_eq!(a.reg.a, 0); // Read on the 3rd M-cycle
+        a.ex();
+        assert_eq!(a.reg.a, 1); // 4th M-cycle of the second instruction
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x04]); // INC B
+        let mut s = a.state();
+        s.b = 0x41;
+        s.f = 0xFF;
+        s.ime = true;
+        a.set_state(&s);
+        a.ex();
+        let s = a.state();
+        assert_eq!(s.b, 0x42);
+        assert_eq!(s.f, 0x10);
+        assert_eq!(s.pc, 0x101);
+        assert_eq!(s.cycles, 1);
+        assert!(s.ime);
+    }
+
+    #[allow(clippy::bool_assert_comparison)]
     #[test]
     fn test_toggle_flags() {
         let mut a: CPU = Default::default();
@@ -1008,4 +1341,100 @@ mod tests {
         assert_eq!(a.flag_c(), false);
         assert_eq!(a.flag_h(), false);
     }
+
+    #[test]
+    fn test_oam_dma_from_hram() {
+        let mut a: CPU = Default::default();
+        load(&mut a, &[0x3E, 0xC0, 0xCD, 0x80, 0xFF]); // LD A, $C0; CALL $FF80
+
+        // LDH ($46), A; LD A, $28; DEC A; JR NZ, -3; RET
+        let routine = [0xE0, 0x46, 0x3E, 0x28, 0x3D, 0x20, 0xFD, 0xC9];
+        for (i, b) in routine.iter().enumerate() {
+            a.mem.set(0xFF80 + i as u16, *b);
+        }
+        for i in 0..0xA0 {
+            a.mem.set(0xC000 + i, !(i as u8));
+        }
+        while a.pc() != 0x105 {
+            a.ex();
+        }
+        assert_eq!(a.mem.get(0xFE00), 0xFF);
+        assert_eq!(a.mem.get(0xFE9F), 0x60);
+    }
+
+    #[test]
+    fn test_flat_ram() {
+        let mut ram = FlatRam::default();
+        ram.data[0x100..0x104].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00]); // LD A, $42; LD ($C000), A
+        ram.data[0x104] = 0xC0;
+        let mut a = CPU::new(ram);
+        a.ex();
+        assert_eq!(a.ex(), 4);
+        assert_eq!(a.mem.data[0xC000], 0x42);
+        assert_eq!(a.pc(), 0x105);
+    }
+
+    #[test]
+    fn test_flat_ram_interrupt() {
+        let mut ram = FlatRam::default();
+        ram.data[0x100] = 0xFB; // EI
+        ram.data[0xFF0F] = Interrupt::Timer.mask();
+        ram.data[0xFFFF] = Interrupt::Timer.mask();
+        let mut a = CPU::new(ram);
+        a.ex();
+        a.ex();
+        assert_eq!(a.ex(), 5);
+        assert_eq!(a.pc(), 0x50);
+        assert_eq!(a.mem.data[0xFF0F], 0);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Access {
+        Read(u16, u8),
+        Write(u16, u8),
+        Tick,
+    }
+
+    #[derive(Default)]
+    struct RecordingBus {
+        ram: FlatRam,
+        log: Vec<Access>,
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            let val = self.ram.read(addr);
+            self.log.push(Access::Read(addr, val));
+            val
+        }
+
+        fn write(&mut self, addr: u16, val: u8) {
+            self.log.push(Access::Write(addr, val));
+            self.ram.write(addr, val);
+        }
+
+        fn tick(&mut self) {
+            self.log.push(Access::Tick);
+        }
+    }
+
+    #[test]
+    fn test_bus_access_order() {
+        let mut a = CPU::new(RecordingBus::default());
+        a.mem.ram.data[0x100] = 0xC5; // PUSH BC
+        a.reg.set_bc(0x1234);
+        a.ex();
+        assert_eq!(
+            a.mem.log,
+            vec![
+                Access::Tick,
+                Access::Read(0x100, 0xC5),
+                Access::Tick,
+                Access::Tick,
+                Access::Write(0xFFFD, 0x12),
+                Access::Tick,
+                Access::Write(0xFFFC, 0x34),
+            ]
+        );
+    }
 }
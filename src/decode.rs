@@ -0,0 +1,316 @@
+/*
+ *  SM83 instruction decoder, shared by the CPU and the disassembler.
+ *
+ *  Opcodes are split into bit fields:
+ *      x = op[7:6], y = op[5:3], z = op[2:0], p = y >> 1, q = y & 1
+ *  which select the instruction group and its operands.
+ */
+
+/*
+ *  8-bit operand. HLInd is the byte in memory at HL.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R8 {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    HLInd,
+    A,
+}
+
+/*
+ *  16-bit operand of LD/INC/DEC/ADD.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R16 {
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+/*
+ *  16-bit operand of PUSH/POP.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R16Stk {
+    BC,
+    DE,
+    HL,
+    AF,
+}
+
+/*
+ *  Indirect operand of LD [r16], A and LD A, [r16]. HLI/HLD post
+ *  increment/decrement HL.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R16Mem {
+    BC,
+    DE,
+    HLI,
+    HLD,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cond {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RotOp {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+}
+
+/*
+ *  Accumulator/flag operations in the x=0, z=7 column.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccOp {
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Instr {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Ld(R8, R8),
+    LdImm(R8, u8),
+    LdR16Imm(R16, u16),
+    LdMemA(R16Mem),
+    LdAMem(R16Mem),
+    LdAbsA(u16), // ld [n16], a
+    LdAAbs(u16), // ld a, [n16]
+    LdhA8A(u8),  // ldh [$FF00+n8], a
+    LdhAA8(u8),  // ldh a, [$FF00+n8]
+    LdhCA,       // ld [$FF00+c], a
+    LdhAC,       // ld a, [$FF00+c]
+    LdAbsSp(u16),
+    LdSpHl,
+    LdHlSp(i8),
+    Inc(R8),
+    Dec(R8),
+    IncR16(R16),
+    DecR16(R16),
+    AddHl(R16),
+    AddSp(i8),
+    Alu(AluOp, R8),
+    AluImm(AluOp, u8),
+    Acc(AccOp),
+    Rot(RotOp, R8),
+    Bit(u8, R8),
+    Res(u8, R8),
+    Set(u8, R8),
+    Jr(Option<Cond>, i8),
+    Jp(Option<Cond>, u16),
+    JpHl,
+    Call(Option<Cond>, u16),
+    Ret(Option<Cond>),
+    Reti,
+    Rst(u8),
+    Push(R16Stk),
+    Pop(R16Stk),
+    Illegal(u8),
+}
+
+const R8_TABLE: [R8; 8] = [R8::B, R8::C, R8::D, R8::E, R8::H, R8::L, R8::HLInd, R8::A];
+const R16_TABLE: [R16; 4] = [R16::BC, R16::DE, R16::HL, R16::SP];
+const R16_STK_TABLE: [R16Stk; 4] = [R16Stk::BC, R16Stk::DE, R16Stk::HL, R16Stk::AF];
+const R16_MEM_TABLE: [R16Mem; 4] = [R16Mem::BC, R16Mem::DE, R16Mem::HLI, R16Mem::HLD];
+const COND_TABLE: [Cond; 4] = [Cond::NZ, Cond::Z, Cond::NC, Cond::C];
+#[rustfmt::skip]
+const ALU_TABLE: [AluOp; 8] = [
+    AluOp::Add, AluOp::Adc, AluOp::Sub, AluOp::Sbc,
+    AluOp::And, AluOp::Xor, AluOp::Or, AluOp::Cp,
+];
+#[rustfmt::skip]
+const ROT_TABLE: [RotOp; 8] = [
+    RotOp::Rlc, RotOp::Rrc, RotOp::Rl, RotOp::Rr,
+    RotOp::Sla, RotOp::Sra, RotOp::Swap, RotOp::Srl,
+];
+#[rustfmt::skip]
+const ACC_TABLE: [AccOp; 8] = [
+    AccOp::Rlca, AccOp::Rrca, AccOp::Rla, AccOp::Rra,
+    AccOp::Daa, AccOp::Cpl, AccOp::Scf, AccOp::Ccf,
+];
+
+/*
+ *  Decode the instruction starting with op. Immediate operands (and the
+ *  second byte of a CB-prefixed instruction) are pulled from fetch.
+ */
+pub fn decode<F: FnMut() -> u8>(op: u8, mut fetch: F) -> Instr {
+    let x = op >> 6;
+    let y = ((op >> 3) & 7) as usize;
+    let z = (op & 7) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => Instr::Nop,
+            1 => Instr::LdAbsSp(n16(&mut fetch)),
+            2 => {
+                fetch(); // STOP is followed by a padding byte
+                Instr::Stop
+            }
+            3 => Instr::Jr(None, fetch() as i8),
+            _ => Instr::Jr(Some(COND_TABLE[y - 4]), fetch() as i8),
+        },
+        (0, 1) if q == 0 => Instr::LdR16Imm(R16_TABLE[p], n16(&mut fetch)),
+        (0, 1) => Instr::AddHl(R16_TABLE[p]),
+        (0, 2) if q == 0 => Instr::LdMemA(R16_MEM_TABLE[p]),
+        (0, 2) => Instr::LdAMem(R16_MEM_TABLE[p]),
+        (0, 3) if q == 0 => Instr::IncR16(R16_TABLE[p]),
+        (0, 3) => Instr::DecR16(R16_TABLE[p]),
+        (0, 4) => Instr::Inc(R8_TABLE[y]),
+        (0, 5) => Instr::Dec(R8_TABLE[y]),
+        (0, 6) => Instr::LdImm(R8_TABLE[y], fetch()),
+        (0, _) => Instr::Acc(ACC_TABLE[y]),
+        (1, 6) if y == 6 => Instr::Halt,
+        (1, _) => Instr::Ld(R8_TABLE[y], R8_TABLE[z]),
+        (2, _) => Instr::Alu(ALU_TABLE[y], R8_TABLE[z]),
+        (_, 0) => match y {
+            0..=3 => Instr::Ret(Some(COND_TABLE[y])),
+            4 => Instr::LdhA8A(fetch()),
+            5 => Instr::AddSp(fetch() as i8),
+            6 => Instr::LdhAA8(fetch()),
+            _ => Instr::LdHlSp(fetch() as i8),
+        },
+        (_, 1) if q == 0 => Instr::Pop(R16_STK_TABLE[p]),
+        (_, 1) => match p {
+            0 => Instr::Ret(None),
+            1 => Instr::Reti,
+            2 => Instr::JpHl,
+            _ => Instr::LdSpHl,
+        },
+        (_, 2) => match y {
+            0..=3 => Instr::Jp(Some(COND_TABLE[y]), n16(&mut fetch)),
+            4 => Instr::LdhCA,
+            5 => Instr::LdAbsA(n16(&mut fetch)),
+            6 => Instr::LdhAC,
+            _ => Instr::LdAAbs(n16(&mut fetch)),
+        },
+        (_, 3) => match y {
+            0 => Instr::Jp(None, n16(&mut fetch)),
+            1 => decode_cb(fetch()),
+            6 => Instr::Di,
+            7 => Instr::Ei,
+            _ => Instr::Illegal(op),
+        },
+        (_, 4) if y < 4 => Instr::Call(Some(COND_TABLE[y]), n16(&mut fetch)),
+        (_, 5) if q == 0 => Instr::Push(R16_STK_TABLE[p]),
+        (_, 5) if p == 0 => Instr::Call(None, n16(&mut fetch)),
+        (_, 6) => Instr::AluImm(ALU_TABLE[y], fetch()),
+        (_, 7) => Instr::Rst((y * 8) as u8),
+        _ => Instr::Illegal(op),
+    }
+}
+
+fn n16<F: FnMut() -> u8>(fetch: &mut F) -> u16 {
+    let lo = fetch();
+    u16::from(fetch()) << 8 | u16::from(lo)
+}
+
+/*
+ *  Decode the byte following a 0xCB prefix.
+ */
+pub fn decode_cb(op: u8) -> Instr {
+    let y = (op >> 3) & 7;
+    let r = R8_TABLE[(op & 7) as usize];
+    match op >> 6 {
+        0 => Instr::Rot(ROT_TABLE[y as usize], r),
+        1 => Instr::Bit(y, r),
+        2 => Instr::Res(y, r),
+        _ => Instr::Set(y, r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> (Instr, usize) {
+        let mut i = 1;
+        let instr = decode(bytes[0], || {
+            let b = bytes.get(i).copied().unwrap_or(0);
+            i += 1;
+            b
+        });
+        (instr, i)
+    }
+
+    #[test]
+    fn test_ld_block() {
+        assert_eq!(decode_bytes(&[0x41]).0, Instr::Ld(R8::B, R8::C));
+        assert_eq!(decode_bytes(&[0x7E]).0, Instr::Ld(R8::A, R8::HLInd));
+        assert_eq!(decode_bytes(&[0x76]).0, Instr::Halt);
+    }
+
+    #[test]
+    fn test_immediates() {
+        assert_eq!(
+            decode_bytes(&[0x01, 0x34, 0x12]),
+            (Instr::LdR16Imm(R16::BC, 0x1234), 3)
+        );
+        assert_eq!(
+            decode_bytes(&[0x38, 0xFE]),
+            (Instr::Jr(Some(Cond::C), -2), 2)
+        );
+        assert_eq!(decode_bytes(&[0x10, 0x00]), (Instr::Stop, 2));
+    }
+
+    #[test]
+    fn test_cb() {
+        assert_eq!(decode_bytes(&[0xCB, 0x7C]), (Instr::Bit(7, R8::H), 2));
+        assert_eq!(
+            decode_bytes(&[0xCB, 0x1E]),
+            (Instr::Rot(RotOp::Rr, R8::HLInd), 2)
+        );
+    }
+
+    #[test]
+    fn test_illegal() {
+        for op in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ]
+        .iter()
+        {
+            assert_eq!(decode_bytes(&[*op]), (Instr::Illegal(*op), 1));
+        }
+    }
+}
@@ -0,0 +1,274 @@
+/*
+ *  SM83 disassembler, producing RGBDS syntax. Decoding is shared with the
+ *  CPU through the decode module.
+ */
+use super::decode::{self, AccOp, AluOp, Cond, Instr, R16Mem, R16Stk, RotOp, R16, R8};
+use super::mmu::MMUnit;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disasm {
+    pub addr: u16,
+    pub len: u8,
+    pub instr: Instr,
+    pub text: String,
+}
+
+impl fmt::Display for Disasm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}: {}", self.addr, self.text)
+    }
+}
+
+impl fmt::Display for R8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            R8::B => "b",
+            R8::C => "c",
+            R8::D => "d",
+            R8::E => "e",
+            R8::H => "h",
+            R8::L => "l",
+            R8::HLInd => "[hl]",
+            R8::A => "a",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for R16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            R16::BC => "bc",
+            R16::DE => "de",
+            R16::HL => "hl",
+            R16::SP => "sp",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for R16Stk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            R16Stk::BC => "bc",
+            R16Stk::DE => "de",
+            R16Stk::HL => "hl",
+            R16Stk::AF => "af",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for R16Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            R16Mem::BC => "[bc]",
+            R16Mem::DE => "[de]",
+            R16Mem::HLI => "[hl+]",
+            R16Mem::HLD => "[hl-]",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Cond::NZ => "nz",
+            Cond::Z => "z",
+            Cond::NC => "nc",
+            Cond::C => "c",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for AluOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AluOp::Add => "add a,",
+            AluOp::Adc => "adc a,",
+            AluOp::Sub => "sub a,",
+            AluOp::Sbc => "sbc a,",
+            AluOp::And => "and a,",
+            AluOp::Xor => "xor a,",
+            AluOp::Or => "or a,",
+            AluOp::Cp => "cp a,",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for RotOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            RotOp::Rlc => "rlc",
+            RotOp::Rrc => "rrc",
+            RotOp::Rl => "rl",
+            RotOp::Rr => "rr",
+            RotOp::Sla => "sla",
+            RotOp::Sra => "sra",
+            RotOp::Swap => "swap",
+            RotOp::Srl => "srl",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for AccOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AccOp::Rlca => "rlca",
+            AccOp::Rrca => "rrca",
+            AccOp::Rla => "rla",
+            AccOp::Rra => "rra",
+            AccOp::Daa => "daa",
+            AccOp::Cpl => "cpl",
+            AccOp::Scf => "scf",
+            AccOp::Ccf => "ccf",
+        };
+        f.write_str(s)
+    }
+}
+
+/*
+ *  Render an instruction. next is the address following it, used to
+ *  resolve relative jump targets.
+ */
+fn format_instr(instr: Instr, next: u16) -> String {
+    let cc = |c: Option<Cond>| match c {
+        Some(c) => format!("{}, ", c),
+        None => String::new(),
+    };
+    match instr {
+        Instr::Nop => "nop".to_string(),
+        Instr::Stop => "stop".to_string(),
+        Instr::Halt => "halt".to_string(),
+        Instr::Di => "di".to_string(),
+        Instr::Ei => "ei".to_string(),
+        Instr::Ld(d, s) => format!("ld {}, {}", d, s),
+        Instr::LdImm(r, n) => format!("ld {}, ${:02X}", r, n),
+        Instr::LdR16Imm(r, n) => format!("ld {}, ${:04X}", r, n),
+        Instr::LdMemA(r) => format!("ld {}, a", r),
+        Instr::LdAMem(r) => format!("ld a, {}", r),
+        Instr::LdAbsA(n) => format!("ld [${:04X}], a", n),
+        Instr::LdAAbs(n) => format!("ld a, [${:04X}]", n),
+        Instr::LdhA8A(n) => format!("ldh [${:04X}], a", 0xFF00 | u16::from(n)),
+        Instr::LdhAA8(n) => format!("ldh a, [${:04X}]", 0xFF00 | u16::from(n)),
+        Instr::LdhCA => "ldh [c], a".to_string(),
+        Instr::LdhAC => "ldh a, [c]".to_string(),
+        Instr::LdAbsSp(n) => format!("ld [${:04X}], sp", n),
+        Instr::LdSpHl => "ld sp, hl".to_string(),
+        Instr::LdHlSp(e) => format!("ld hl, sp{:+}", e),
+        Instr::Inc(r) => format!("inc {}", r),
+        Instr::Dec(r) => format!("dec {}", r),
+        Instr::IncR16(r) => format!("inc {}", r),
+        Instr::DecR16(r) => format!("dec {}", r),
+        Instr::AddHl(r) => format!("add hl, {}", r),
+        Instr::AddSp(e) => format!("add sp, {}", e),
+        Instr::Alu(op, r) => format!("{} {}", op, r),
+        Instr::AluImm(op, n) => format!("{} ${:02X}", op, n),
+        Instr::Acc(op) => op.to_string(),
+        Instr::Rot(op, r) => format!("{} {}", op, r),
+        Instr::Bit(b, r) => format!("bit {}, {}", b, r),
+        Instr::Res(b, r) => format!("res {}, {}", b, r),
+        Instr::Set(b, r) => format!("set {}, {}", b, r),
+        Instr::Jr(c, e) => format!("jr {}${:04X}", cc(c), next.wrapping_add(e as u16)),
+        Instr::Jp(c, n) => format!("jp {}${:04X}", cc(c), n),
+        Instr::JpHl => "jp hl".to_string(),
+        Instr::Call(c, n) => format!("call {}${:04X}", cc(c), n),
+        Instr::Ret(Some(c)) => format!("ret {}", c),
+        Instr::Ret(None) => "ret".to_string(),
+        Instr::Reti => "reti".to_string(),
+        Instr::Rst(n) => format!("rst ${:02X}", n),
+        Instr::Push(r) => format!("push {}", r),
+        Instr::Pop(r) => format!("pop {}", r),
+        Instr::Illegal(op) => format!("db ${:02X}", op),
+    }
+}
+
+/*
+ *  Disassemble the instruction at the start of bytes, which was read
+ *  from addr. Missing trailing bytes are treated as 0x00.
+ */
+pub fn disassemble(bytes: &[u8], addr: u16) -> Disasm {
+    let mut len = 1;
+    let instr = decode::decode(bytes.first().copied().unwrap_or(0), || {
+        let b = bytes.get(len).copied().unwrap_or(0);
+        len += 1;
+        b
+    });
+    let next = addr.wrapping_add(len as u16);
+    Disasm {
+        addr,
+        len: len as u8,
+        instr,
+        text: format_instr(instr, next),
+    }
+}
+
+/*
+ *  Disassemble the instruction at addr in the memory map.
+ */
+pub fn disassemble_mem(mem: &MMUnit, addr: u16) -> Disasm {
+    let bytes = [
+        mem.get(addr),
+        mem.get(addr.wrapping_add(1)),
+        mem.get(addr.wrapping_add(2)),
+    ];
+    disassemble(&bytes, addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+
+    fn text(bytes: &[u8]) -> String {
+        disassemble(bytes, 0x0100).text
+    }
+
+    #[test]
+    fn test_lengths() {
+        for op in 0..=0xFFu8 {
+            let d = disassemble(&[op, 0, 0], 0);
+            assert!(d.len >= 1 && d.len <= 3, "{:02X}", op);
+        }
+        assert_eq!(disassemble(&[0xCB, 0x7C], 0).len, 2);
+        assert_eq!(disassemble(&[0x10, 0x00], 0).len, 2);
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(text(&[0x00]), "nop");
+        assert_eq!(text(&[0x31, 0xFE, 0xFF]), "ld sp, $FFFE");
+        assert_eq!(text(&[0x22]), "ld [hl+], a");
+        assert_eq!(text(&[0x7E]), "ld a, [hl]");
+        assert_eq!(text(&[0x76]), "halt");
+        assert_eq!(text(&[0xAF]), "xor a, a");
+        assert_eq!(text(&[0xE0, 0x40]), "ldh [$FF40], a");
+        assert_eq!(text(&[0xE2]), "ldh [c], a");
+        assert_eq!(text(&[0xF2]), "ldh a, [c]");
+        assert_eq!(text(&[0xF8, 0xFE]), "ld hl, sp-2");
+        assert_eq!(text(&[0xE8, 0x05]), "add sp, 5");
+        assert_eq!(text(&[0xCD, 0x34, 0x12]), "call $1234");
+        assert_eq!(text(&[0xC2, 0x34, 0x12]), "jp nz, $1234");
+        assert_eq!(text(&[0xFF]), "rst $38");
+        assert_eq!(text(&[0xD3]), "db $D3");
+    }
+
+    #[test]
+    fn test_relative_target() {
+        assert_eq!(text(&[0x18, 0xFE]), "jr $0100");
+        assert_eq!(text(&[0x20, 0x05]), "jr nz, $0107");
+    }
+
+    #[test]
+    fn test_cb() {
+        assert_eq!(text(&[0xCB, 0x7C]), "bit 7, h");
+        assert_eq!(text(&[0xCB, 0x36]), "swap [hl]");
+        assert_eq!(text(&[0xCB, 0x87]), "res 0, a");
+        assert_eq!(text(&[0xCB, 0xFE]), "set 7, [hl]");
+        assert_eq!(text(&[0xCB, 0x11]), "rl c");
+    }
+}
@@ -1,12 +1,459 @@
+use super::archive;
+pub use super::cartridge::{CgbSupport, LoadError, Mapper, RtcClock, ROM};
+pub use super::cpu::IllegalOpcode;
 use super::cpu::CPU;
+use super::disasm::{self, Disasm};
+pub use super::joypad::Button;
+use super::mmu::{self, MMUnit};
+pub use super::mmu::{Interrupt, Model};
+pub use super::state::CpuState;
+pub use super::watch::{Access, Hit, HookId};
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
 
-#[derive(Default)]
 pub struct GameBoy {
     cpu: CPU,
+    rumble: bool,
+    on_rumble: Option<Box<dyn FnMut(bool)>>,
+}
+
+impl Default for GameBoy {
+    fn default() -> GameBoy {
+        GameBoy::new(Model::Dmg)
+    }
 }
 
 impl GameBoy {
-    pub fn load_rom(&mut self) {
-        self.cpu.mem.load_rom();
+    /*
+     *  A system started in the state its boot ROM would leave it in,
+     *  without running one.
+     */
+    pub fn new(model: Model) -> GameBoy {
+        let mut mem = MMUnit::new(model);
+        mem.skip_boot();
+        let mut cpu = CPU::new(mem);
+        cpu.set_state(&CpuState::post_boot(model));
+        GameBoy::with_cpu(cpu)
+    }
+
+    /*
+     *  A system that powers on into the given boot ROM (256 bytes for
+     *  DMG, 2304 for CGB). The boot ROM unmaps itself by writing FF50
+     *  before jumping to the cartridge.
+     */
+    pub fn with_boot_rom(model: Model, boot: Vec<u8>) -> GameBoy {
+        let mut mem = MMUnit::new(model);
+        mem.set_boot_rom(boot);
+        let mut cpu = CPU::new(mem);
+        cpu.set_state(&CpuState::default());
+        GameBoy::with_cpu(cpu)
+    }
+
+    fn with_cpu(cpu: CPU) -> GameBoy {
+        GameBoy {
+            cpu,
+            rumble: false,
+            on_rumble: None,
+        }
+    }
+
+    /*
+     *  Load a ROM file and start it in the post-boot state of a model
+     *  picked from the header: CGB for carts with CGB support, DMG
+     *  otherwise. The file may be a .gz, or a .zip holding a single ROM.
+     */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<GameBoy, LoadError> {
+        GameBoy::from_archive(path, None)
+    }
+
+    /*
+     *  As from_path(), picking the ROM named entry out of a zip file. The
+     *  name may be the entry's full path or just its file name.
+     *
+     *  An IPS, UPS or BPS patch with the same base name as the file (e.g.
+     *  game.ips for game.gb or game.zip) is applied automatically.
+     */
+    pub fn from_archive<P: AsRef<Path>>(
+        path: P,
+        entry: Option<&str>,
+    ) -> Result<GameBoy, LoadError> {
+        GameBoy::open(path.as_ref(), entry, None)
+    }
+
+    /*
+     *  As from_archive(), applying the given patch file instead of
+     *  looking for one.
+     */
+    pub fn from_patched<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        entry: Option<&str>,
+        patch: Q,
+    ) -> Result<GameBoy, LoadError> {
+        GameBoy::open(path.as_ref(), entry, Some(patch.as_ref()))
+    }
+
+    fn open(path: &Path, entry: Option<&str>, patch: Option<&Path>) -> Result<GameBoy, LoadError> {
+        let mut gb = GameBoy::from_image(mmu::read_rom(path, entry, patch)?)?;
+        gb.cpu.mem.set_rom_path(path);
+        Ok(gb)
+    }
+
+    /*
+     *  As from_path(), for an image (or archive) already in memory.
+     *  Battery backed RAM is not persisted as there is no file to save
+     *  next to.
+     */
+    pub fn from_bytes(rom: &[u8]) -> Result<GameBoy, LoadError> {
+        GameBoy::from_image(archive::unpack(rom.to_vec(), None)?)
+    }
+
+    fn from_image(rom: Vec<u8>) -> Result<GameBoy, LoadError> {
+        let model = match ROM::parse(&rom).cgb {
+            CgbSupport::Dmg => Model::Dmg,
+            _ => Model::Cgb,
+        };
+        let mut gb = GameBoy::new(model);
+        gb.cpu.mem.load_bytes(rom)?;
+        Ok(gb)
+    }
+
+    /*
+     *  Insert a cartridge from a ROM file into an existing system, e.g.
+     *  one created with_boot_rom().
+     */
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        self.cpu.mem.load_rom(path.as_ref(), None)
+    }
+
+    /*
+     *  Header of the loaded cartridge.
+     */
+    pub fn rom_info(&self) -> &ROM {
+        self.cpu.mem.rom_info()
+    }
+
+    /*
+     *  Execute a single instruction, returning the M-cycles it took.
+     *  An illegal opcode locks the CPU like hardware does; later steps
+     *  only let time pass.
+     */
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.cpu.ex();
+        self.check_rumble();
+        cycles
+    }
+
+    /*
+     *  As step(), but reports an illegal opcode (from this or an earlier
+     *  step) as an error so harnesses can detect runaway code.
+     */
+    pub fn try_step(&mut self) -> Result<u32, IllegalOpcode> {
+        if let Some(err) = self.cpu.locked() {
+            return Err(err);
+        }
+        let cycles = self.cpu.ex();
+        self.check_rumble();
+        match self.cpu.locked() {
+            Some(err) => Err(err),
+            None => Ok(cycles),
+        }
+    }
+
+    /*
+     *  Raise an interrupt request (set its bit in IF).
+     */
+    pub fn request_interrupt(&mut self, i: Interrupt) {
+        self.cpu.mem.request_interrupt(i);
+    }
+
+    pub fn press(&mut self, b: Button) {
+        self.cpu.mem.press(b);
+    }
+
+    pub fn release(&mut self, b: Button) {
+        self.cpu.mem.release(b);
+    }
+
+    /*
+     *  Whether the CPU is running in CGB double speed mode.
+     */
+    pub fn double_speed(&self) -> bool {
+        self.cpu.mem.double_speed()
+    }
+
+    /*
+     *  Write battery backed cartridge RAM to its save file now, rather
+     *  than waiting for the periodic flush or shutdown.
+     */
+    pub fn save(&mut self) -> io::Result<()> {
+        self.cpu.mem.flush_save()
+    }
+
+    /*
+     *  Select whether the cartridge real-time clock follows emulated
+     *  cycles (the default, deterministic) or the host clock.
+     */
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.cpu.mem.set_rtc_clock(clock);
+    }
+
+    /*
+     *  Whether the cartridge's rumble motor is on.
+     */
+    pub fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    /*
+     *  Whether the cartridge's infrared LED is lit (HuC1 and HuC3).
+     *  Nothing ever shines back, so games see no link partner.
+     */
+    pub fn infrared(&self) -> bool {
+        self.cpu.mem.infrared()
+    }
+
+    /*
+     *  Register a function called with the new motor state whenever a
+     *  rumble cartridge switches its motor on or off.
+     */
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, f: F) {
+        self.on_rumble = Some(Box::new(f));
+    }
+
+    fn check_rumble(&mut self) {
+        let rumble = self.cpu.mem.rumble();
+        if rumble == self.rumble {
+            return;
+        }
+        self.rumble = rumble;
+        if let Some(f) = self.on_rumble.as_mut() {
+            f(rumble);
+        }
+    }
+
+    /*
+     *  Call f on every CPU read, write or opcode fetch in range, only
+     *  while bank bank is mapped there if one is given. Returns an
+     *  id for remove_hook().
+     */
+    pub fn add_hook<F: FnMut(&Hit) + 'static>(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+        f: F,
+    ) -> HookId {
+        self.cpu.mem.add_hook(access, range, bank, f)
+    }
+
+    /*
+     *  As add_hook(), but matching accesses are recorded for
+     *  take_watchpoint() so a debugger can stop after the step.
+     */
+    pub fn add_watchpoint(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+    ) -> HookId {
+        self.cpu.mem.add_watchpoint(access, range, bank)
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.cpu.mem.remove_hook(id)
+    }
+
+    /*
+     *  The first watchpoint hit since the last call, if any.
+     */
+    pub fn take_watchpoint(&mut self) -> Option<Hit> {
+        self.cpu.mem.take_watchpoint()
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn set_cpu_state(&mut self, s: &CpuState) {
+        self.cpu.set_state(s);
+    }
+
+    /*
+     *  Disassemble the instruction at addr.
+     */
+    pub fn disassemble(&self, addr: u16) -> Disasm {
+        disasm::disassemble_mem(&self.cpu.mem, addr)
+    }
+
+    /*
+     *  Disassemble the instruction the CPU will execute next.
+     */
+    pub fn disassemble_next(&self) -> Disasm {
+        self.disassemble(self.cpu.pc())
+    }
+
+    /*
+     *  Total M-cycles executed since power on.
+     */
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::rom_image;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_post_boot_state() {
+        let g = GameBoy::new(Model::Dmg);
+        let s = g.cpu_state();
+        assert_eq!(
+            (s.pc, s.sp, s.af(), s.bc()),
+            (0x100, 0xFFFE, 0x01B0, 0x0013)
+        );
+        assert_eq!((s.de(), s.hl()), (0x00D8, 0x014D));
+        let s = GameBoy::new(Model::Cgb).cpu_state();
+        assert_eq!(
+            (s.af(), s.bc(), s.de(), s.hl()),
+            (0x1180, 0x0000, 0xFF56, 0x000D)
+        );
+    }
+
+    #[test]
+    fn test_boot_rom_hand_over() {
+        let mut boot = vec![0; 0x100];
+        boot[0..3].copy_from_slice(&[0xC3, 0xFC, 0x00]); // JP $00FC
+        boot[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]); // LD A, $01; LDH ($50), A
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3C; // INC A
+        let mut g = GameBoy::with_boot_rom(Model::Dmg, boot);
+        g.cpu.mem.set_rom(rom);
+        assert_eq!(g.cpu_state().pc, 0x0000);
+        assert_eq!(g.disassemble(0x0000).text, "jp $00FC");
+        for _ in 0..4 {
+            g.step();
+        }
+        assert_eq!(g.cpu_state().a, 0x02);
+        assert_eq!(g.disassemble(0x0000).text, "nop");
+    }
+
+    fn image(cgb: u8) -> Vec<u8> {
+        rom_image(b"TEST", cgb, 0x00)
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let g = GameBoy::from_bytes(&image(0x00)).unwrap();
+        assert_eq!(g.cpu.mem.model(), Model::Dmg);
+        assert_eq!(g.cpu_state().a, 0x01);
+        let g = GameBoy::from_bytes(&image(0x80)).unwrap();
+        assert_eq!(g.cpu.mem.model(), Model::Cgb);
+        assert_eq!(g.cpu_state().a, 0x11);
+        assert!(GameBoy::from_bytes(&[0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn test_from_path() {
+        let path = std::env::temp_dir().join(format!("gameboy-gb-{}.gb", std::process::id()));
+        fs::write(&path, image(0x00)).unwrap();
+        let g = GameBoy::from_path(&path).unwrap();
+        assert_eq!(g.rom_info().filename, path.display().to_string());
+        fs::remove_file(&path).unwrap();
+        match GameBoy::from_path(&path) {
+            Err(LoadError::Io(_)) => {}
+            _ => panic!("expected an I/O error"),
+        }
+    }
+
+    #[test]
+    fn test_from_patched() {
+        let dir = std::env::temp_dir().join(format!("gameboy-gb-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("game.gb"), image(0x00)).unwrap();
+        // Set the CGB flag, fixing up the header checksum to match
+        let cgb = image(0x80);
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x01, 0x43, 0x00, 0x01, 0x80]);
+        ips.extend_from_slice(&[0x00, 0x01, 0x4D, 0x00, 0x01, cgb[0x14D]]);
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("cgb.ips"), &ips).unwrap();
+
+        let g = GameBoy::from_path(dir.join("game.gb")).unwrap();
+        assert_eq!(g.cpu.mem.model(), Model::Dmg);
+        let g = GameBoy::from_patched(dir.join("game.gb"), None, dir.join("cgb.ips")).unwrap();
+        assert_eq!(g.cpu.mem.model(), Model::Cgb);
+        fs::rename(dir.join("cgb.ips"), dir.join("game.ips")).unwrap();
+        let g = GameBoy::from_path(dir.join("game.gb")).unwrap();
+        assert_eq!(g.cpu.mem.model(), Model::Cgb);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /*
+     *  Run a blargg test ROM, collecting what it prints over the serial
+     *  port, until it reports a result.
+     */
+    fn run_blargg(g: &mut GameBoy) -> String {
+        let mut out = String::new();
+        for _ in 0..10_000_000 {
+            g.step();
+            if g.cpu.mem.get(0xFF02) == 0x81 {
+                out.push(g.cpu.mem.get(0xFF01) as char);
+                g.cpu.mem.set(0xFF02, 0x00);
+                if out.contains("Passed") || out.contains("Failed") {
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_bundled_archive() {
+        let zip = concat!(env!("CARGO_MANIFEST_DIR"), "/data/cpu_instrs.zip");
+        let mut g = GameBoy::from_archive(zip, Some("06-ld r,r.gb")).unwrap();
+        assert_eq!(g.rom_info().cart_type_name(), "MBC1");
+        assert!(run_blargg(&mut g).contains("Passed"));
+        assert!(GameBoy::from_path(zip).is_err()); // Twelve ROMs to choose from
+    }
+
+    #[test]
+    fn test_catch_rom_write() {
+        let mut rom = image(0x00);
+        rom[0x100..0x104].copy_from_slice(&[0xAF, 0xEA, 0x00, 0x60]); // XOR A; LD ($6000), A
+        let mut g = GameBoy::from_bytes(&rom).unwrap();
+        g.add_watchpoint(Access::Write, 0x0000..=0x7FFF, None);
+        g.step();
+        assert_eq!(g.take_watchpoint(), None);
+        g.step();
+        let hit = g.take_watchpoint().unwrap();
+        assert_eq!((hit.addr, hit.val), (0x6000, 0x00));
+    }
+
+    #[test]
+    fn test_rumble_callback() {
+        let mut rom = vec![0; 0x8000];
+        // MBC5+RUMBLE
+        rom[0x147] = 0x1C;
+        // LD A, $08; LD ($4000), A; XOR A; LD ($4000), A
+        rom[0x100..0x109].copy_from_slice(&[0x3E, 0x08, 0xEA, 0x00, 0x40, 0xAF, 0xEA, 0x00, 0x40]);
+        let mut g = GameBoy::default();
+        g.cpu.mem.set_rom(rom);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&events);
+        g.on_rumble(move |on| log.borrow_mut().push(on));
+        g.step();
+        assert!(events.borrow().is_empty());
+        g.step();
+        assert!(g.rumble());
+        g.step();
+        g.step();
+        assert!(!g.rumble());
+        assert_eq!(*events.borrow(), vec![true, false]);
     }
 }
@@ -0,0 +1,91 @@
+/*
+ *  Joypad, exposed through P1 (0xFF00).
+ *
+ *  Bits 4-5 select which group drives the low nibble (0 = selected):
+ *      bit 4 - Direction keys (Right, Left, Up, Down)
+ *      bit 5 - Button keys (A, B, Select, Start)
+ *  Bits 0-3 read 0 for a pressed key in a selected group.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+#[derive(Default)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8, // One bit per Button, set while held
+}
+
+impl Joypad {
+    pub fn get(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn set(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+
+    /*
+     *  Press a button. Returns true when a selected input line went from
+     *  high to low, which requests the Joypad interrupt.
+     */
+    pub fn press(&mut self, b: Button) -> bool {
+        let before = self.get();
+        self.pressed |= b.mask();
+        (before & !self.get() & 0x0F) != 0
+    }
+
+    pub fn release(&mut self, b: Button) {
+        self.pressed &= !b.mask();
+    }
+
+    pub fn any_pressed(&self) -> bool {
+        self.pressed != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Button, Joypad};
+
+    #[test]
+    fn test_select_groups() {
+        let mut a = Joypad::default();
+        a.press(Button::Start);
+        a.set(0x10); // Buttons selected
+        assert_eq!(a.get() & 0x0F, 0x07);
+        a.set(0x20); // Directions selected
+        assert_eq!(a.get() & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn test_press_edge() {
+        let mut a = Joypad::default();
+        a.set(0x20);
+        assert!(a.press(Button::Up));
+        assert!(!a.press(Button::A));
+        a.release(Button::Up);
+        assert!(a.any_pressed());
+    }
+}
@@ -1,4 +1,14 @@
-mod cpu;
+mod archive;
+pub mod bus;
+mod cartridge;
+pub mod cpu;
+pub mod decode;
+pub mod disasm;
 pub mod gb;
+mod joypad;
 mod mmu;
+mod patch;
 mod register;
+mod state;
+mod timer;
+mod watch;
@@ -1,8 +1,24 @@
 extern crate gameboy;
 
 use gameboy::gb::GameBoy;
+use std::env;
+use std::process;
 
 fn main() {
-    let mut a = GameBoy::default();
-    a.load_rom();
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: gameboy <rom> [zip entry]");
+            process::exit(2);
+        }
+    };
+    let entry = env::args().nth(2);
+    let gb = match GameBoy::from_archive(&path, entry.as_deref()) {
+        Ok(gb) => gb,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    println!("{}", gb.rom_info());
 }
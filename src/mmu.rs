@@ -1,103 +1,1090 @@
-use std::fmt;
+use super::archive;
+use super::bus::Bus;
+use super::cartridge::{self, Cartridge, LoadError, RtcClock, ROM};
+use super::joypad::{Button, Joypad};
+use super::patch;
+use super::timer::Timer;
+use super::watch::{Access, Hit, HookId, Hooks};
 use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
+/*
+ *  M-cycles (about a second) between a write to battery backed RAM and
+ *  the save file being updated.
+ */
+const SAVE_DELAY: u32 = 1 << 20;
+
+/*
+ *  Memory map:
+ *      0000-3FFF   ROM bank 0 (switchable on some controllers)
+ *      4000-7FFF   ROM bank 1-N
+ *      8000-9FFF   Video RAM
+ *      A000-BFFF   External (cartridge) RAM
+ *      C000-DFFF   Work RAM
+ *      E000-FDFF   Echo of C000-DDFF
+ *      FE00-FE9F   Object Attribute Memory
+ *      FEA0-FEFF   Unusable
+ *      FF00-FF7F   I/O registers
+ *      FF80-FFFE   High RAM
+ *      FFFF        Interrupt Enable
+ *
+ *  Reads from anything unmapped return the open bus value 0xFF.
+ *
+ *  Writing XX to FF46 starts an OAM DMA, copying XX00-XX9F to OAM at one
+ *  byte per M-cycle after a cycle of startup, during which a transfer
+ *  already running carries on. While it runs the CPU only has HRAM and
+ *  the I/O registers to itself: OAM reads 0xFF, and accesses to the bus
+ *  the DMA is reading from (VRAM, or the external bus for everything
+ *  else) see the byte being transferred and drop writes.
+ */
 pub struct MMUnit {
-    data: Vec<u8>,
+    model: Model,
+    boot_rom: Vec<u8>, // Overlaid on the cartridge until FF50 is written
+    cart: Box<dyn Cartridge>,
+    rtc_clock: RtcClock,
+    save_path: Option<PathBuf>, // Set for battery backed cartridges
+    save_dirty: bool,
+    save_timer: u32, // M-cycles since the save became dirty
+    vram: [u8; 0x2000],
+    wram: [u8; 0x2000],
+    oam: [u8; 0xA0],
+    io: [u8; 0x80], // Backing store for I/O registers without their own handling
+    hram: [u8; 0x7F],
     rom_info: ROM,
+    ie: u8,   // Interrupt Enable (0xFFFF)
+    intf: u8, // Interrupt Flag (0xFF0F)
+    joypad: Joypad,
+    timer: Timer,
+    double_speed: bool, // CGB speed mode, KEY1 (0xFF4D) bit 7
+    speed_armed: bool,  // KEY1 bit 0, a STOP performs the speed switch
+    dma_source: u16,
+    dma_start: u8, // M-cycles until a DMA written to FF46 takes over, 0 if none
+    dma_index: u8, // Next byte to copy, OAM_SIZE when idle
+    dma_byte: u8,  // Last byte the DMA read, seen by conflicting reads
+    hooks: Hooks,
 }
 
-impl Default for MMUnit {
-    fn default() -> MMUnit {
-        let vec: Vec<u8> = vec![0; 0xFFFF];
-        MMUnit {
-            data: vec,
-            rom_info: ROM::default(),
+const OAM_SIZE: u8 = 0xA0;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+/*
+ *  I/O register values left behind by the boot ROM, from Pan Docs.
+ *  Registers with their own handling (P1, timer, IF) are set separately.
+ */
+#[rustfmt::skip]
+const POST_BOOT_IO: [(u16, u8); 23] = [
+    (0xFF02, 0x7E), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3),
+    (0xFF13, 0xFF), (0xFF14, 0xBF), (0xFF16, 0x3F), (0xFF18, 0xFF),
+    (0xFF19, 0xBF), (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF), (0xFF1E, 0xBF), (0xFF20, 0xFF), (0xFF23, 0xBF),
+    (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91),
+    (0xFF41, 0x85), (0xFF46, 0xFF), (0xFF47, 0xFC),
+];
+
+/*
+ *  I/O registers kept as plain bytes in io until the hardware behind
+ *  them (serial, sound, LCD, and on CGB VRAM/WRAM banking, HDMA and
+ *  palettes) is emulated. Anything else in FF00-FF7F without its own
+ *  handling is unmapped.
+ */
+fn plain_io(model: Model, addr: u16) -> bool {
+    match addr {
+        0xFF01 | 0xFF02 | 0xFF10..=0xFF14 | 0xFF16..=0xFF1E | 0xFF20..=0xFF26 | 0xFF30..=0xFF4B => {
+            true
         }
+        0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => model == Model::Cgb,
+        _ => false,
+    }
+}
+
+/*
+ *  Bus an address is on from the point of view of OAM DMA conflicts.
+ */
+#[derive(PartialEq)]
+enum DmaBus {
+    External,
+    Video,
+    Internal, // OAM, I/O and HRAM
+}
+
+fn dma_bus(addr: u16) -> DmaBus {
+    match addr {
+        0x8000..=0x9FFF => DmaBus::Video,
+        0xFE00..=0xFFFF => DmaBus::Internal,
+        _ => DmaBus::External,
     }
 }
 
-struct ROM {
-    filename: String,
-    title: String,
+/*
+ *  Interrupt sources, in priority order. The discriminant is the bit
+ *  used in both IE and IF.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
 }
 
-impl Default for ROM {
-    fn default() -> ROM {
-        ROM {
-            filename: "test_file".to_string(),
-            title: "test".to_string(),
+impl Interrupt {
+    pub fn from_bit(bit: u8) -> Interrupt {
+        match bit {
+            0 => Interrupt::VBlank,
+            1 => Interrupt::Stat,
+            2 => Interrupt::Timer,
+            3 => Interrupt::Serial,
+            _ => Interrupt::Joypad,
         }
     }
+
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * (self as u16)
+    }
 }
 
-impl fmt::Display for ROM {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "ROM:\n\tFilename: {}\n\tTitle: {}",
-            self.filename, self.title
-        )
+impl Default for MMUnit {
+    fn default() -> MMUnit {
+        MMUnit::new(Model::Dmg)
     }
 }
 
 impl MMUnit {
+    /*
+     *  Memory in its power on state. Call skip_boot() when starting
+     *  without a boot ROM.
+     */
+    pub fn new(model: Model) -> MMUnit {
+        MMUnit {
+            model,
+            boot_rom: Vec::new(),
+            cart: cartridge::load(Vec::new(), &ROM::default()),
+            rtc_clock: RtcClock::default(),
+            save_path: None,
+            save_dirty: false,
+            save_timer: 0,
+            vram: [0; 0x2000],
+            wram: [0; 0x2000],
+            oam: [0; 0xA0],
+            io: [0; 0x80],
+            hram: [0; 0x7F],
+            rom_info: ROM::default(),
+            ie: 0,
+            intf: 0,
+            joypad: Joypad::default(),
+            timer: Timer::default(),
+            double_speed: false,
+            speed_armed: false,
+            dma_source: 0,
+            dma_start: 0,
+            dma_index: OAM_SIZE,
+            dma_byte: 0xFF,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /*
+     *  Map a boot ROM over 0000-00FF, and 0200-08FF on CGB, until the
+     *  program writes to FF50.
+     */
+    pub fn set_boot_rom(&mut self, boot: Vec<u8>) {
+        self.boot_rom = boot;
+    }
+
+    /*
+     *  Put the I/O registers in the state the boot ROM leaves them in.
+     */
+    pub fn skip_boot(&mut self) {
+        for &(addr, val) in POST_BOOT_IO.iter() {
+            self.io[(addr - 0xFF00) as usize] = val;
+        }
+        self.intf = Interrupt::VBlank.mask();
+        match self.model {
+            Model::Dmg => self.timer.set_counter(0xABCC),
+            Model::Cgb => {
+                self.timer.set_counter(0x267C);
+                self.io[0x02] = 0x7F;
+                self.io[0x46] = 0x00;
+            }
+        }
+        self.boot_rom = Vec::new();
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    fn boot_byte(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x00FF => self.boot_rom.get(addr as usize).copied(),
+            0x0200..=0x08FF if self.model == Model::Cgb => {
+                self.boot_rom.get(addr as usize).copied()
+            }
+            _ => None,
+        }
+    }
+
     pub fn set(&mut self, addr: u16, val: u8) {
-        // TODO: Value may be u8
-        self.data[addr as usize] = val;
+        match addr {
+            0x0000..=0x7FFF => self.cart.write_rom(addr, val),
+            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize] = val,
+            0xA000..=0xBFFF => {
+                // Only battery backed carts have a save path
+                if self.cart.write_ram(addr, val) && self.save_path.is_some() {
+                    self.save_dirty = true;
+                }
+            }
+            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize] = val,
+            0xE000..=0xFDFF => self.wram[(addr - 0xE000) as usize] = val,
+            0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = val,
+            0xFEA0..=0xFEFF => {}
+            0xFF00..=0xFF7F => self.set_io(addr, val),
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = val,
+            0xFFFF => self.ie = val,
+        }
     }
 
     pub fn get(&self, addr: u16) -> u8 {
-        self.data[addr as usize]
+        match addr {
+            0x0000..=0x7FFF => match self.boot_byte(addr) {
+                Some(b) => b,
+                None => self.cart.read_rom(addr),
+            },
+            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize],
+            0xA000..=0xBFFF => self.cart.read_ram(addr),
+            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize],
+            0xE000..=0xFDFF => self.wram[(addr - 0xE000) as usize],
+            0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00..=0xFF7F => self.get_io(addr),
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
+            0xFFFF => self.ie,
+        }
+    }
+
+    fn set_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => self.joypad.set(val),
+            0xFF04..=0xFF07 => {
+                if self.timer.set(addr, val) {
+                    self.request_interrupt(Interrupt::Timer);
+                }
+            }
+            0xFF0F => self.intf = val & 0x1F,
+            0xFF4D if self.model == Model::Cgb => self.speed_armed = (val & 0x01) != 0,
+            0xFF46 => {
+                // The source is latched from io once the startup cycle is over
+                self.io[0x46] = val;
+                self.dma_start = 2;
+            }
+            0xFF50 => {
+                if val != 0 {
+                    self.boot_rom = Vec::new();
+                }
+            }
+            _ => {
+                if plain_io(self.model, addr) {
+                    self.io[(addr - 0xFF00) as usize] = val;
+                }
+            }
+        }
+    }
+
+    fn get_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => self.joypad.get(),
+            0xFF04..=0xFF07 => self.timer.get(addr),
+            0xFF0F => self.intf | 0xE0, // Upper 3 bits are unused and read as 1
+            0xFF4D if self.model == Model::Cgb => {
+                0x7E | (u8::from(self.double_speed) << 7) | u8::from(self.speed_armed)
+            }
+            _ if plain_io(self.model, addr) => self.io[(addr - 0xFF00) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    /*
+     *  A read as the CPU sees it while OAM DMA may be running.
+     */
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if self.dma_active() {
+            if (0xFE00..=0xFE9F).contains(&addr) {
+                return 0xFF;
+            }
+            if self.dma_conflict(addr) {
+                return self.dma_byte;
+            }
+        }
+        self.get(addr)
+    }
+
+    fn dma_active(&self) -> bool {
+        self.dma_index < OAM_SIZE
+    }
+
+    /*
+     *  Copy one byte. Sources above DFFF read the echo of work RAM.
+     */
+    fn dma_step(&mut self) {
+        let mut src = self.dma_source + u16::from(self.dma_index);
+        if src >= 0xE000 {
+            src -= 0x2000;
+        }
+        self.dma_byte = self.get(src);
+        self.oam[self.dma_index as usize] = self.dma_byte;
+        self.dma_index += 1;
+    }
+
+    /*
+     *  Whether a CPU access to addr collides with a running DMA.
+     */
+    fn dma_conflict(&self, addr: u16) -> bool {
+        self.dma_active() && dma_bus(addr) == dma_bus(self.dma_source)
     }
 
     pub fn get_hw(&self, addr: u16) -> u16 {
-        ((u16::from(self.data[(addr + 1) as usize]) << 8) | u16::from(self.data[(addr) as usize]))
-            as u16
+        (u16::from(self.get(addr.wrapping_add(1))) << 8) | u16::from(self.get(addr))
     }
 
     pub fn set_hw(&mut self, addr: u16, val: u16) {
-        self.data[addr as usize] = (val & 0x0F) as u8;
-        self.data[(addr + 1) as usize] = (val & 0xF0) as u8;
-    }
-
-    pub fn load_rom(&mut self) {
-        //TODO pass filename/path
-        let dir =
-            String::from("C:\\Code\\rust\\gameboy\\data\\cpu_instrs\\individual\\01-special.gb");
-        //let dir = String::from("/home/ryan/code/rust/gameboy/data/cpu_instrs/individual/01-special.gb");
-        let rom_data: Vec<u8> = fs::read(&dir).expect("Unable to read file");
-        let rom = ROM {
-            filename: dir,
-            title: "test".to_string(),
-        };
-        self.rom_info = rom;
-        self.data[..rom_data.len()].clone_from_slice(&rom_data);
+        self.set(addr, (val & 0xFF) as u8);
+        self.set(addr.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    pub fn request_interrupt(&mut self, i: Interrupt) {
+        self.intf |= i.mask();
+    }
+
+    pub fn press(&mut self, b: Button) {
+        if self.joypad.press(b) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn release(&mut self, b: Button) {
+        self.joypad.release(b);
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn rom_info(&self) -> &ROM {
+        &self.rom_info
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.cart.rumble()
+    }
+
+    pub fn infrared(&self) -> bool {
+        self.cart.infrared()
+    }
+
+    /*
+     *  Call f on every CPU access of the given kind to addresses in range,
+     *  only while bank bank is mapped there if one is given.
+     */
+    pub fn add_hook<F: FnMut(&Hit) + 'static>(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+        f: F,
+    ) -> HookId {
+        self.hooks.add(access, range, bank, f)
+    }
+
+    /*
+     *  As add_hook(), recording the first matching access for
+     *  take_watchpoint() instead of calling a function.
+     */
+    pub fn add_watchpoint(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+    ) -> HookId {
+        self.hooks.add_watchpoint(access, range, bank)
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.hooks.remove(id)
+    }
+
+    /*
+     *  The first access to hit a watchpoint since the last call, if any.
+     */
+    pub fn take_watchpoint(&mut self) -> Option<Hit> {
+        self.hooks.take_hit()
+    }
+
+    fn fire(&mut self, access: Access, addr: u16, val: u8) {
+        let bank = self.bank(addr);
+        self.hooks.fire(Hit {
+            access,
+            addr,
+            bank,
+            val,
+        });
+    }
+
+    /*
+     *  Load the ROM image at path (see read_rom). Battery backed RAM is
+     *  kept in a .sav file next to it.
+     */
+    pub fn load_rom(&mut self, path: &Path, entry: Option<&str>) -> Result<(), LoadError> {
+        let rom = read_rom(path, entry, None)?;
+        self.load_bytes(rom)?;
+        self.set_rom_path(path);
+        Ok(())
+    }
+
+    /*
+     *  Record where the loaded ROM came from and attach its save file.
+     */
+    pub fn set_rom_path(&mut self, path: &Path) {
+        self.attach_save(archive::base_path(path).with_extension("sav"));
+        self.rom_info.filename = path.display().to_string();
+    }
+
+    /*
+     *  Validate and insert a ROM image. Unlike set_rom(), images that
+     *  couldn't run on hardware are rejected.
+     */
+    pub fn load_bytes(&mut self, rom: Vec<u8>) -> Result<(), LoadError> {
+        cartridge::validate(&rom, &ROM::parse(&rom))?;
+        self.set_rom(rom);
+        Ok(())
+    }
+
+    /*
+     *  Insert a cartridge built from a ROM image. The bank controller
+     *  and external RAM are taken from the header.
+     */
+    pub fn set_rom(&mut self, rom: Vec<u8>) {
+        self.close_save();
+        self.save_path = None;
+        self.rom_info = ROM::parse(&rom);
+        self.cart = cartridge::load(rom, &self.rom_info);
+        self.cart.set_rtc_clock(self.rtc_clock);
+    }
+
+    /*
+     *  Keep the cartridge's battery backed state in the file at path,
+     *  loading it now if it exists. Does nothing for carts without a
+     *  battery.
+     */
+    pub fn attach_save(&mut self, path: PathBuf) {
+        if !self.rom_info.has_battery() {
+            return;
+        }
+        if let Ok(data) = fs::read(&path) {
+            self.cart.load_save(&data);
+        }
+        self.save_path = Some(path);
+        self.save_dirty = false;
+    }
+
+    /*
+     *  Write the battery backed state to the save file.
+     */
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.save_dirty = false;
+        self.save_timer = 0;
+        match &self.save_path {
+            Some(path) => fs::write(path, self.cart.save_data()),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     *  Flush the save when the cartridge is removed. RAM that was never
+     *  written is left alone so a failed load can't be overwritten, but
+     *  a clock always is so its footer records when the game stopped.
+     */
+    fn close_save(&mut self) {
+        if self.save_dirty || self.rom_info.has_rtc() {
+            let _ = self.flush_save();
+        }
+    }
+
+    /*
+     *  Choose the time source of the cartridge clock, if it has one.
+     */
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.rtc_clock = clock;
+        self.cart.set_rtc_clock(clock);
+    }
+}
+
+impl Drop for MMUnit {
+    fn drop(&mut self) {
+        self.close_save();
+    }
+}
+
+/*
+ *  Read a ROM image from path, which may be zipped or gzipped (see
+ *  archive::unpack for entry), and apply a patch: the one given, or an
+ *  .ips/.ups/.bps file with the same base name if there is one.
+ */
+pub fn read_rom(
+    path: &Path,
+    entry: Option<&str>,
+    patch: Option<&Path>,
+) -> Result<Vec<u8>, LoadError> {
+    let rom = archive::unpack(fs::read(path)?, entry)?;
+    let patch = match patch {
+        Some(patch) => Some(patch.to_path_buf()),
+        None => patch::find(&archive::base_path(path)),
+    };
+    match patch {
+        Some(patch) => patch::apply(rom, &fs::read(patch)?),
+        None => Ok(rom),
+    }
+}
+
+impl Bus for MMUnit {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = self.cpu_read(addr);
+        if self.hooks.watching(Access::Read) {
+            self.fire(Access::Read, addr, val);
+        }
+        val
+    }
+
+    fn fetch(&mut self, addr: u16) -> u8 {
+        let val = self.cpu_read(addr);
+        if self.hooks.watching(Access::Execute) {
+            self.fire(Access::Execute, addr, val);
+        }
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        if self.hooks.watching(Access::Write) {
+            self.fire(Access::Write, addr, val);
+        }
+        if self.dma_active() && ((0xFE00..=0xFE9F).contains(&addr) || self.dma_conflict(addr)) {
+            return;
+        }
+        self.set(addr, val);
+    }
+
+    fn tick(&mut self) {
+        if self.dma_start > 0 {
+            self.dma_start -= 1;
+            if self.dma_start == 0 {
+                self.dma_source = u16::from(self.io[0x46]) << 8;
+                self.dma_index = 0;
+            }
+        }
+        if self.dma_active() {
+            self.dma_step();
+        }
+        // The cartridge runs from its own crystal, unaffected by CPU speed
+        self.cart.tick(if self.double_speed { 2 } else { 4 });
+        if self.save_dirty {
+            self.save_timer += 1;
+            if self.save_timer >= SAVE_DELAY {
+                // Failures are retried on the next write or at shutdown
+                let _ = self.flush_save();
+            }
+        }
+        if self.timer.tick() {
+            self.request_interrupt(Interrupt::Timer);
+        }
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.intf & self.ie & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, i: Interrupt) {
+        self.intf &= !i.mask();
+    }
+
+    fn reset_divider(&mut self) {
+        self.set_io(0xFF04, 0);
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        if !self.speed_armed {
+            return false;
+        }
+        self.speed_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    fn joypad_pressed(&self) -> bool {
+        self.joypad.any_pressed()
+    }
+
+    fn bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x7FFF => self.cart.rom_bank(addr),
+            0xA000..=0xBFFF => self.cart.ram_bank(),
+            // SVBK, bank 0 selecting 1 as on hardware
+            0xD000..=0xDFFF | 0xF000..=0xFDFF if self.model == Model::Cgb => {
+                u16::from(self.io[0x70] & 0x07).max(1)
+            }
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => 1,
+            _ => 0,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MMUnit;
+    use super::{read_rom, Access, Bus, Hit, Interrupt, LoadError, MMUnit, Model, SAVE_DELAY};
+    use crate::cpu::CPU;
+    use crate::state::CpuState;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
 
     #[test]
     fn test_write() {
         let mut a = MMUnit::default();
-        a.set(0, 10);
+        a.set(0xC000, 10);
     }
 
     #[test]
     fn test_read() {
         let val = 10;
         let mut a = MMUnit::default();
-        a.set(0, val);
-        let b = a.get(0);
+        a.set(0xC000, val);
+        let b = a.get(0xC000);
         assert_eq!(val, b);
     }
 
+    #[test]
+    fn test_rom_read_only() {
+        let mut a = MMUnit::default();
+        a.set_rom(vec![0x12; 0x8000]);
+        a.set(0x0000, 0x34);
+        a.set(0x7FFF, 0x34);
+        assert_eq!(a.get(0x0000), 0x12);
+        assert_eq!(a.get(0x7FFF), 0x12);
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mut a = MMUnit::default();
+        a.set(0xC123, 0x55);
+        assert_eq!(a.get(0xE123), 0x55);
+        a.set(0xFDFF, 0x66);
+        assert_eq!(a.get(0xDDFF), 0x66);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut a = MMUnit::default();
+        a.set(0xFEA0, 0x00);
+        assert_eq!(a.get(0xFEA0), 0xFF);
+        assert_eq!(a.get(0xA000), 0xFF); // No cartridge RAM
+        assert_eq!(a.get(0x0100), 0xFF); // No ROM loaded
+    }
+
+    #[test]
+    fn test_unmapped_io() {
+        let mut a = MMUnit::default();
+        for &addr in [0xFF03, 0xFF08, 0xFF27, 0xFF4C, 0xFF7F].iter() {
+            assert_eq!(a.get(addr), 0xFF);
+            a.set(addr, 0x12);
+            assert_eq!(a.get(addr), 0xFF);
+        }
+        a.set(0xFF01, 0x12); // Serial data is still kept
+        assert_eq!(a.get(0xFF01), 0x12);
+
+        // CGB only registers
+        let mut c = MMUnit::new(Model::Cgb);
+        for &addr in [0xFF4F, 0xFF51, 0xFF55, 0xFF68, 0xFF6B, 0xFF70].iter() {
+            a.set(addr, 0x12);
+            assert_eq!(a.get(addr), 0xFF);
+            c.set(addr, 0x12);
+            assert_eq!(c.get(addr), 0x12);
+        }
+    }
+
+    #[test]
+    fn test_ie_and_hram() {
+        let mut a = MMUnit::default();
+        a.set(0xFFFE, 0x11);
+        a.set(0xFFFF, 0x1F);
+        assert_eq!(a.get(0xFFFE), 0x11);
+        assert_eq!(a.get(0xFFFF), 0x1F);
+    }
+
+    #[test]
+    fn test_interrupt_registers() {
+        let mut a = MMUnit::default();
+        a.set(0xFFFF, 0x05);
+        assert_eq!(a.get(0xFFFF), 0x05);
+        a.request_interrupt(Interrupt::Timer);
+        a.request_interrupt(Interrupt::Serial);
+        assert_eq!(a.get(0xFF0F), 0xEC);
+        assert_eq!(a.pending_interrupts(), 0x04);
+    }
+
+    #[test]
+    fn test_cartridge_mapped() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02;
+        rom[0xC000] = 0x33;
+        let mut a = MMUnit::default();
+        a.set_rom(rom);
+        a.set(0x2000, 0x03);
+        assert_eq!(a.get(0x4000), 0x33);
+        assert_eq!(a.bank(0x4000), 3);
+        a.set(0x0000, 0x0A);
+        a.set(0xA000, 0x44);
+        assert_eq!(a.get(0xA000), 0x44);
+    }
+
+    fn battery_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02;
+        rom
+    }
+
+    fn save_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gameboy-{}-{}.sav", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let path = save_path("round-trip");
+        let mut a = MMUnit::default();
+        a.set_rom(battery_rom());
+        a.attach_save(path.clone());
+        a.set(0x0000, 0x0A);
+        a.set(0xA123, 0x5A);
+        drop(a);
+        assert_eq!(fs::read(&path).unwrap()[0x123], 0x5A);
+
+        let mut b = MMUnit::default();
+        b.set_rom(battery_rom());
+        b.attach_save(path.clone());
+        b.set(0x0000, 0x0A);
+        assert_eq!(b.get(0xA123), 0x5A);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_periodic_flush() {
+        let path = save_path("periodic");
+        let mut a = MMUnit::default();
+        a.set_rom(battery_rom());
+        a.attach_save(path.clone());
+        a.set(0x0000, 0x0A);
+        a.set(0xA000, 0x11);
+        for _ in 0..SAVE_DELAY - 1 {
+            a.tick();
+        }
+        assert!(!path.exists());
+        a.tick();
+        assert_eq!(fs::read(&path).unwrap()[0], 0x11);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disabled_ram_write_keeps_save_clean() {
+        let path = save_path("disabled");
+        let mut a = MMUnit::default();
+        a.set_rom(battery_rom());
+        a.attach_save(path.clone());
+        a.set(0xA000, 0x11); // RAM not enabled
+        assert!(!a.save_dirty);
+        a.set(0x0000, 0x0A);
+        a.set(0xA000, 0x11);
+        assert!(a.save_dirty);
+        a.flush_save().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_untouched_save_not_written() {
+        let path = save_path("untouched");
+        let mut a = MMUnit::default();
+        a.set_rom(battery_rom());
+        a.attach_save(path.clone());
+        a.set(0x0000, 0x0A);
+        a.get(0xA000);
+        drop(a);
+        assert!(!path.exists());
+
+        // A clock is written anyway, to record when it stopped
+        let mut rom = battery_rom();
+        rom[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+        let mut b = MMUnit::default();
+        b.set_rom(rom);
+        b.attach_save(path.clone());
+        b.set_rom(battery_rom());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_battery_no_save() {
+        let path = save_path("no-battery");
+        let mut rom = battery_rom();
+        rom[0x147] = 0x02; // MBC1+RAM
+        let mut a = MMUnit::default();
+        a.set_rom(rom);
+        a.attach_save(path.clone());
+        a.set(0x0000, 0x0A);
+        a.set(0xA000, 0x11);
+        assert!(!a.save_dirty);
+        drop(a);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_boot_rom_overlay() {
+        let mut rom = vec![0x11; 0x8000];
+        rom[0x147] = 0x00;
+        let mut a = MMUnit::new(Model::Cgb);
+        a.set_rom(rom);
+        a.set_boot_rom(vec![0x22; 0x900]);
+        assert_eq!(a.get(0x0000), 0x22);
+        assert_eq!(a.get(0x00FF), 0x22);
+        assert_eq!(a.get(0x0100), 0x11); // Cartridge header stays visible
+        assert_eq!(a.get(0x08FF), 0x22);
+        assert_eq!(a.get(0x0900), 0x11);
+        a.set(0xFF50, 0x00);
+        assert_eq!(a.get(0x0000), 0x22);
+        a.set(0xFF50, 0x01);
+        assert_eq!(a.get(0x0000), 0x11);
+        assert_eq!(a.get(0x0200), 0x11);
+    }
+
+    #[test]
+    fn test_dmg_boot_rom_size() {
+        let mut a = MMUnit::new(Model::Dmg);
+        a.set_rom(vec![0x11; 0x8000]);
+        a.set_boot_rom(vec![0x22; 0x900]);
+        assert_eq!(a.get(0x00FF), 0x22);
+        assert_eq!(a.get(0x0200), 0x11);
+    }
+
+    #[test]
+    fn test_post_boot_io() {
+        let mut a = MMUnit::new(Model::Dmg);
+        a.skip_boot();
+        assert_eq!(a.get(0xFF00), 0xCF);
+        assert_eq!(a.get(0xFF04), 0xAB);
+        assert_eq!(a.get(0xFF07), 0xF8);
+        assert_eq!(a.get(0xFF0F), 0xE1);
+        assert_eq!(a.get(0xFF26), 0xF1);
+        assert_eq!(a.get(0xFF40), 0x91);
+        assert_eq!(a.get(0xFF47), 0xFC);
+        assert_eq!(a.get(0xFF4D), 0xFF); // No KEY1 on DMG
+        let mut c = MMUnit::new(Model::Cgb);
+        c.skip_boot();
+        assert_eq!(c.get(0xFF02), 0x7F);
+        assert_eq!(c.get(0xFF04), 0x26);
+        assert_eq!(c.get(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut a = MMUnit::default();
+        for i in 0..0xA0 {
+            a.set(0xC100 + i, i as u8);
+        }
+        a.set(0xFE00, 0xAA);
+        a.write(0xFF46, 0xC1);
+        assert_eq!(a.get(0xFF46), 0xC1);
+        a.tick(); // Startup, OAM is still free
+        assert_eq!(a.read(0xFE00), 0xAA);
+        for _ in 0..159 {
+            a.tick();
+        }
+        assert_eq!(a.read(0xFE00), 0xFF);
+        assert_eq!(a.get(0xFE9E), 0x9E);
+        assert_eq!(a.get(0xFE9F), 0x00); // Last byte still to come
+        a.tick();
+        assert_eq!(a.get(0xFE9F), 0x9F);
+        assert_eq!(a.read(0xFE10), 0x10);
+    }
+
+    #[test]
+    fn test_oam_dma_conflicts() {
+        let mut a = MMUnit::default();
+        a.set(0xC000, 0x42);
+        a.set(0xD000, 0x99);
+        a.set(0x8000, 0x77);
+        a.set(0xFF80, 0x55);
+        a.write(0xFF46, 0xC0);
+        a.tick();
+        assert_eq!(a.read(0xD000), 0x99);
+        a.tick();
+        assert_eq!(a.read(0xD000), 0x42); // Sees the byte being copied
+        assert_eq!(a.read(0xFE00), 0xFF);
+        assert_eq!(a.read(0x8000), 0x77); // VRAM is on another bus
+        assert_eq!(a.read(0xFF80), 0x55);
+        a.write(0xD000, 0x11);
+        a.write(0xFF81, 0x66);
+        assert_eq!(a.get(0xD000), 0x99);
+        assert_eq!(a.get(0xFF81), 0x66);
+        for _ in 0..159 {
+            a.tick();
+        }
+        assert_eq!(a.read(0xD000), 0x99);
+    }
+
+    #[test]
+    fn test_oam_dma_from_echo() {
+        let mut a = MMUnit::default();
+        a.set(0xDE05, 0x33);
+        a.write(0xFF46, 0xFE);
+        for _ in 0..161 {
+            a.tick();
+        }
+        assert_eq!(a.get(0xFE05), 0x33);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut a = MMUnit::default();
+        a.set_rom(battery_rom());
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&writes);
+        let id = a.add_hook(Access::Write, 0x0000..=0x7FFF, None, move |h| {
+            log.borrow_mut().push((h.addr, h.val))
+        });
+        a.add_watchpoint(Access::Read, 0xC000..=0xC0FF, None);
+        a.write(0x2000, 0x01);
+        a.write(0xC010, 0x42);
+        assert_eq!(a.take_watchpoint(), None);
+        assert_eq!(a.read(0xC010), 0x42);
+        assert_eq!(
+            a.take_watchpoint(),
+            Some(Hit {
+                access: Access::Read,
+                addr: 0xC010,
+                bank: 0,
+                val: 0x42
+            })
+        );
+        a.get(0xC010); // Peeks don't count
+        assert_eq!(a.take_watchpoint(), None);
+        assert!(a.remove_hook(id));
+        a.write(0x2000, 0x02);
+        assert_eq!(*writes.borrow(), vec![(0x2000, 0x01)]);
+    }
+
+    #[test]
+    fn test_execute_hook_by_bank() {
+        let mut rom = vec![0; 0x10000]; // 4 banks of NOPs
+        rom[0x147] = 0x01; // MBC1
+        rom[0x100..0x105].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20]); // LD A, $02; LD ($2000), A
+        rom[0x105..0x108].copy_from_slice(&[0xC3, 0x00, 0x40]); // JP $4000
+        let mut a = MMUnit::default();
+        a.set_rom(rom);
+        a.add_watchpoint(Access::Execute, 0x4000..=0x7FFF, Some(1));
+        a.add_watchpoint(Access::Execute, 0x4000..=0x7FFF, Some(2));
+        let mut cpu = CPU::new(a);
+        cpu.set_state(&CpuState::post_boot(Model::Dmg));
+        for _ in 0..3 {
+            cpu.ex();
+        }
+        assert_eq!(cpu.mem.take_watchpoint(), None);
+        cpu.ex();
+        let hit = cpu.mem.take_watchpoint().unwrap();
+        assert_eq!((hit.addr, hit.bank, hit.val), (0x4000, 2, 0x00));
+    }
+
+    #[test]
+    fn test_ram_hooks_by_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x1A; // MBC5+RAM
+        rom[0x149] = 0x03; // 32K
+        let mut a = MMUnit::new(Model::Cgb);
+        a.set_rom(rom);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&writes);
+        a.add_hook(Access::Write, 0xA000..=0xBFFF, Some(2), move |h| {
+            log.borrow_mut().push((h.addr, h.bank))
+        });
+        a.add_watchpoint(Access::Write, 0xD000..=0xDFFF, Some(3));
+        a.write(0x0000, 0x0A);
+        a.write(0xA000, 0x11); // Bank 0
+        a.write(0x4000, 0x02);
+        a.write(0xA001, 0x22);
+        a.write(0x4000, 0x06); // Wraps to bank 2
+        a.write(0xA002, 0x33);
+        assert_eq!(*writes.borrow(), vec![(0xA001, 2), (0xA002, 2)]);
+
+        a.write(0xD000, 0x44); // SVBK 0 maps bank 1
+        assert_eq!(a.take_watchpoint(), None);
+        a.write(0xFF70, 0x03);
+        a.write(0xD000, 0x55);
+        assert_eq!(a.take_watchpoint().map(|h| h.bank), Some(3));
+    }
+
     #[test]
     fn test_open_file() {
+        let path = std::env::temp_dir().join(format!("gameboy-open-{}.gb", std::process::id()));
+        let mut rom = vec![0; 0x8000];
+        rom[0x104] = 0xCE;
+        rom[0x14D] = 0xE7; // Header checksum of an all zero header
+        fs::write(&path, &rom).unwrap();
         let mut a = MMUnit::default();
-        a.load_rom();
-        assert_eq!(a.data.is_empty(), false);
+        a.load_rom(&path, None).unwrap();
+        assert_eq!(a.get(0x0104), 0xCE); // First byte of the header logo
+        assert_eq!(a.rom_info().filename, path.display().to_string());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_patch_found_next_to_rom() {
+        let dir = std::env::temp_dir().join(format!("gameboy-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = 0xE7;
+        fs::write(dir.join("game.gb"), &rom).unwrap();
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x01, 0x50, 0x00, 0x01, 0x3C]);
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+
+        let mut a = MMUnit::default();
+        a.load_rom(&dir.join("game.gb"), None).unwrap();
+        assert_eq!(a.get(0x0150), 0x3C);
+        let err = read_rom(&dir.join("game.gb"), None, Some(&dir.join("game.gb"))).unwrap_err();
+        assert!(matches!(err, LoadError::BadPatch(_)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        let mut a = MMUnit::default();
+        match a.load_rom(Path::new("/nonexistent/rom.gb"), None) {
+            Err(LoadError::Io(_)) => {}
+            r => panic!("{:?}", r),
+        }
     }
 }
@@ -0,0 +1,438 @@
+/*
+ *  ROM patches in the IPS, UPS and BPS formats, applied in memory before
+ *  the header is parsed. The format is recognised by its magic bytes.
+ *
+ *  IPS records are a 24-bit offset and 16-bit length followed by the
+ *  data, or a zero length, 16-bit count and fill byte for a run. UPS and
+ *  BPS end with CRC32s of the source, the target and the patch itself,
+ *  all of which are checked.
+ */
+use super::cartridge::LoadError;
+use flate2::Crc;
+use std::path::{Path, PathBuf};
+
+/*
+ *  Patch files that are picked up automatically next to a ROM, in the
+ *  order they are looked for.
+ */
+const EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+/*
+ *  Find a patch with the same base name as the ROM at base.
+ */
+pub fn find(base: &Path) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| base.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(&rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(&rom, patch)
+    } else {
+        Err(LoadError::BadPatch("unknown patch format"))
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/*
+ *  Reader over patch bytes that reports truncation as a load error.
+ */
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Reader<'a> {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len());
+        match end {
+            Some(end) => {
+                let b = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(b)
+            }
+            None => Err(LoadError::BadPatch("truncated patch")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, n: usize) -> Result<usize, LoadError> {
+        Ok(self
+            .bytes(n)?
+            .iter()
+            .fold(0, |v, &b| v << 8 | usize::from(b)))
+    }
+
+    fn le32(&mut self) -> Result<u32, LoadError> {
+        Ok(self
+            .bytes(4)?
+            .iter()
+            .rev()
+            .fold(0, |v, &b| v << 8 | u32::from(b)))
+    }
+
+    /*
+     *  Variable length number used by UPS and BPS: 7 bits per byte, least
+     *  significant first, with bit 7 marking the last byte.
+     */
+    fn varint(&mut self) -> Result<usize, LoadError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let b = self.byte()?;
+            value = usize::from(b & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(LoadError::BadPatch("number out of range"))?;
+            if b & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_mul(128)
+                .ok_or(LoadError::BadPatch("number out of range"))?;
+            value += shift;
+        }
+    }
+}
+
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let mut r = Reader::new(patch, 5);
+    loop {
+        let offset = r.be(3)?;
+        if offset == 0x454F46 {
+            break; // "EOF"
+        }
+        let (len, fill) = match r.be(2)? {
+            0 => (r.be(2)?, Some(r.byte()?)),
+            len => (len, None),
+        };
+        if rom.len() < offset + len {
+            rom.resize(offset + len, 0);
+        }
+        match fill {
+            Some(b) => rom[offset..offset + len].iter_mut().for_each(|v| *v = b),
+            None => rom[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+    // An optional 24-bit size after EOF truncates the ROM
+    if let Ok(size) = r.be(3) {
+        rom.truncate(size);
+    }
+    Ok(rom)
+}
+
+/*
+ *  Split off and check the CRC32 footer shared by UPS and BPS. Returns
+ *  the body and the expected source and target CRCs.
+ */
+fn checked_body(patch: &[u8]) -> Result<(&[u8], u32, u32), LoadError> {
+    if patch.len() < 4 + 12 {
+        return Err(LoadError::BadPatch("truncated patch"));
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let mut r = Reader::new(footer, 0);
+    let (source, target, expected) = (r.le32()?, r.le32()?, r.le32()?);
+    check_crc("patch", &patch[..patch.len() - 4], expected)?;
+    Ok((body, source, target))
+}
+
+fn check_crc(what: &'static str, data: &[u8], expected: u32) -> Result<(), LoadError> {
+    let actual = crc32(data);
+    if actual != expected {
+        return Err(LoadError::PatchCrc(what, expected, actual));
+    }
+    Ok(())
+}
+
+/*
+ *  UPS hunks skip ahead a number of bytes, then XOR the source with patch
+ *  bytes up to a zero terminator, which also covers one byte.
+ */
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let (body, source_crc, target_crc) = checked_body(patch)?;
+    check_crc("source", rom, source_crc)?;
+    let mut r = Reader::new(body, 4);
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    if source_size != rom.len() {
+        return Err(LoadError::BadPatch("patch is for a ROM of another size"));
+    }
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while r.pos < body.len() {
+        pos = pos.saturating_add(r.varint()?);
+        loop {
+            let x = r.byte()?;
+            if x != 0 {
+                match out.get_mut(pos) {
+                    Some(b) => *b ^= x,
+                    None => return Err(LoadError::BadPatch("hunk past end of ROM")),
+                }
+            }
+            pos = pos.saturating_add(1);
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_crc("target", &out, target_crc)?;
+    Ok(out)
+}
+
+/*
+ *  BPS builds the target from commands that copy from the source at the
+ *  same offset, take literal bytes from the patch, or copy from a moving
+ *  offset in the source or in the target written so far.
+ */
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let (body, source_crc, target_crc) = checked_body(patch)?;
+    check_crc("source", rom, source_crc)?;
+    let mut r = Reader::new(body, 4);
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata = r.varint()?;
+    r.bytes(metadata)?;
+    if source_size != rom.len() {
+        return Err(LoadError::BadPatch("patch is for a ROM of another size"));
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_rel: usize = 0;
+    let mut target_rel: usize = 0;
+    let relative = |r: &mut Reader, base: usize| -> Result<usize, LoadError> {
+        let data = r.varint()?;
+        let delta = data >> 1;
+        let offset = if data & 1 != 0 {
+            base.checked_sub(delta)
+        } else {
+            base.checked_add(delta)
+        };
+        offset.ok_or(LoadError::BadPatch("copy offset out of range"))
+    };
+    while r.pos < body.len() {
+        let data = r.varint()?;
+        let len = (data >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err(LoadError::BadPatch("command past end of target"));
+        }
+        match data & 3 {
+            0 => {
+                let from = out.len();
+                match rom.get(from..from + len) {
+                    Some(src) => out.extend_from_slice(src),
+                    None => return Err(LoadError::BadPatch("source read past end of ROM")),
+                }
+            }
+            1 => out.extend_from_slice(r.bytes(len)?),
+            2 => {
+                source_rel = relative(&mut r, source_rel)?;
+                match rom.get(source_rel..source_rel + len) {
+                    Some(src) => out.extend_from_slice(src),
+                    None => return Err(LoadError::BadPatch("source copy past end of ROM")),
+                }
+                source_rel += len;
+            }
+            _ => {
+                target_rel = relative(&mut r, target_rel)?;
+                // Byte by byte, the copy may overlap what it is writing
+                for _ in 0..len {
+                    match out.get(target_rel) {
+                        Some(&b) => out.push(b),
+                        None => return Err(LoadError::BadPatch("target copy past end of target")),
+                    }
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(LoadError::BadPatch("target size mismatch"));
+    }
+    check_crc("target", &out, target_crc)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            v -= 1;
+        }
+    }
+
+    /*
+     *  Append the source, target and patch CRC footer.
+     */
+    fn seal(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn rom() -> Vec<u8> {
+        (0..0x200).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_varint() {
+        for &v in [0, 1, 127, 128, 300, 0x4000, 0x123456].iter() {
+            assert_eq!(Reader::new(&varint(v), 0).varint().unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x02, 0xAA, 0xBB]); // 0100: AA BB
+        patch.extend_from_slice(&[0x00, 0x01, 0x10, 0x00, 0x00, 0x00, 0x03, 0xCC]); // 0110: 3x CC
+        patch.extend_from_slice(&[0x00, 0x02, 0x00, 0x00, 0x01, 0xDD]); // Grows the ROM
+        patch.extend_from_slice(b"EOF");
+        let out = apply(rom(), &patch).unwrap();
+        assert_eq!(&out[0x100..0x103], &[0xAA, 0xBB, 0x02]);
+        assert_eq!(&out[0x10F..0x114], &[0x0F, 0xCC, 0xCC, 0xCC, 0x13]);
+        assert_eq!(out.len(), 0x201);
+        assert_eq!(out[0x200], 0xDD);
+    }
+
+    #[test]
+    fn test_ips_truncate() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x01, 0x00]);
+        assert_eq!(apply(rom(), &patch).unwrap().len(), 0x100);
+        patch.truncate(patch.len() - 3);
+        patch.truncate(patch.len() - 1); // Missing EOF marker
+        match apply(rom(), &patch) {
+            Err(LoadError::BadPatch(_)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    fn ups_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        let mut last = 0;
+        let mut i = 0;
+        while i < target.len() {
+            let s = source.get(i).copied().unwrap_or(0);
+            if s == target[i] {
+                i += 1;
+                continue;
+            }
+            patch.extend(varint(i - last));
+            while i < target.len() && source.get(i).copied().unwrap_or(0) != target[i] {
+                patch.push(source.get(i).copied().unwrap_or(0) ^ target[i]);
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            last = i;
+        }
+        seal(patch, source, target)
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = rom();
+        let mut target = source.clone();
+        target[0x10] = 0xFF;
+        target[0x11] = 0xFE;
+        target[0x150] = 0x00;
+        target.extend_from_slice(&[1, 2, 3]);
+        let patch = ups_patch(&source, &target);
+        assert_eq!(apply(source.clone(), &patch).unwrap(), target);
+
+        let mut other = source.clone();
+        other[0] = 0xFF;
+        match apply(other, &patch) {
+            Err(LoadError::PatchCrc("source", ..)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 0x01;
+        match apply(source, &corrupt) {
+            Err(LoadError::PatchCrc("patch", ..)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = rom();
+        let mut target = Vec::new();
+        target.extend_from_slice(&source[..0x10]); // SourceRead
+        target.extend_from_slice(b"HELLO"); // TargetRead
+        target.extend_from_slice(&source[0x100..0x108]); // SourceCopy
+        let tail = [source[0x106], source[0x107]];
+        target.extend_from_slice(&tail.repeat(3)); // TargetCopy, overlapping
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(3));
+        patch.extend_from_slice(b"abc"); // Metadata
+        patch.extend(varint((0x10 - 1) << 2));
+        patch.extend(varint(((5 - 1) << 2) | 1));
+        patch.extend_from_slice(b"HELLO");
+        patch.extend(varint(((8 - 1) << 2) | 2));
+        patch.extend(varint(0x100 << 1));
+        patch.extend(varint(((6 - 1) << 2) | 3));
+        patch.extend(varint(0x1B << 1)); // The last two bytes written
+        let patch = seal(patch, &source, &target);
+        assert_eq!(apply(source.clone(), &patch).unwrap(), target);
+
+        let mut bad = seal(patch[..patch.len() - 12].to_vec(), &source, b"wrong");
+        match apply(source.clone(), &bad) {
+            Err(LoadError::PatchCrc("target", ..)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+        bad = seal(b"BPS1\x80".to_vec(), &source, &target);
+        match apply(source, &bad) {
+            Err(LoadError::BadPatch(_)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        match apply(rom(), b"NOTAPATCH") {
+            Err(LoadError::BadPatch(_)) => {}
+            r => panic!("{:?}", r.map(|r| r.len())),
+        }
+    }
+}
@@ -37,28 +37,28 @@ impl Register {
     }
     pub fn set_af(&mut self, val: u16) {
         self.a = (val >> 8) as u8;
-        self.f = (val & 0x0F) as u8;
+        self.f = (val & 0xF0) as u8; // Lower nibble of F always reads 0
     }
     pub fn get_bc(&self) -> u16 {
         u16::from(self.b) << 8 | u16::from(self.c)
     }
     pub fn set_bc(&mut self, val: u16) {
         self.b = (val >> 8) as u8;
-        self.c = (val & 0x0F) as u8;
+        self.c = (val & 0xFF) as u8;
     }
     pub fn get_de(&self) -> u16 {
         u16::from(self.d) << 8 | u16::from(self.e)
     }
     pub fn set_de(&mut self, val: u16) {
         self.d = (val >> 8) as u8;
-        self.e = (val & 0x0F) as u8;
+        self.e = (val & 0xFF) as u8;
     }
     pub fn get_hl(&self) -> u16 {
         u16::from(self.h) << 8 | u16::from(self.l)
     }
     pub fn set_hl(&mut self, val: u16) {
         self.h = (val >> 8) as u8;
-        self.l = (val & 0x0F) as u8;
+        self.l = (val & 0xFF) as u8;
     }
 }
 
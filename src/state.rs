@@ -0,0 +1,161 @@
+/*
+ *  Snapshot of the CPU's programmer visible state, for tests and external
+ *  tooling. Read with GameBoy::cpu_state() and written back with
+ *  GameBoy::set_cpu_state().
+ */
+use super::mmu::Model;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub f: u8, // Z N H C 0 0 0 0, lower nibble is ignored on write
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub ime: bool,
+    pub halted: bool,
+    pub cycles: u64, // Total M-cycles executed
+}
+
+impl CpuState {
+    /*
+     *  Registers as the boot ROM leaves them when handing over to the
+     *  cartridge at 0x100.
+     */
+    pub fn post_boot(model: Model) -> CpuState {
+        let (a, f, b, c, d, e, h, l) = match model {
+            Model::Dmg => (0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+        CpuState {
+            pc: 0x100,
+            sp: 0xFFFE,
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            ..CpuState::default()
+        }
+    }
+
+    pub fn af(&self) -> u16 {
+        u16::from(self.a) << 8 | u16::from(self.f)
+    }
+    pub fn bc(&self) -> u16 {
+        u16::from(self.b) << 8 | u16::from(self.c)
+    }
+    pub fn de(&self) -> u16 {
+        u16::from(self.d) << 8 | u16::from(self.e)
+    }
+    pub fn hl(&self) -> u16 {
+        u16::from(self.h) << 8 | u16::from(self.l)
+    }
+
+    pub fn flag_z(&self) -> bool {
+        (self.f & 1 << 7) != 0
+    }
+    pub fn flag_n(&self) -> bool {
+        (self.f & 1 << 6) != 0
+    }
+    pub fn flag_h(&self) -> bool {
+        (self.f & 1 << 5) != 0
+    }
+    pub fn flag_c(&self) -> bool {
+        (self.f & 1 << 4) != 0
+    }
+
+    /*
+     *  Structured dump as a single JSON object. Register values are
+     *  plain numbers.
+     */
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"pc\":{},\"sp\":{},\"a\":{},\"f\":{},\"b\":{},\"c\":{},\"d\":{},\"e\":{},\"h\":{},\"l\":{},\"ime\":{},\"halted\":{},\"cycles\":{}}}",
+            self.pc,
+            self.sp,
+            self.a,
+            self.f,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.ime,
+            self.halted,
+            self.cycles
+        )
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "PC: {:04X} SP: {:04X} AF: {:04X} BC: {:04X} DE: {:04X} HL: {:04X} F: {}{}{}{} IME: {} HALT: {} CYC: {}",
+            self.pc,
+            self.sp,
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            flag(self.flag_z(), 'Z'),
+            flag(self.flag_n(), 'N'),
+            flag(self.flag_h(), 'H'),
+            flag(self.flag_c(), 'C'),
+            u8::from(self.ime),
+            u8::from(self.halted),
+            self.cycles
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuState;
+
+    fn state() -> CpuState {
+        CpuState {
+            pc: 0x0150,
+            sp: 0xFFFE,
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            ime: true,
+            halted: false,
+            cycles: 42,
+        }
+    }
+
+    #[test]
+    fn test_display_hex() {
+        assert_eq!(
+            state().to_string(),
+            "PC: 0150 SP: FFFE AF: 01B0 BC: 0013 DE: 00D8 HL: 014D F: Z-HC IME: 1 HALT: 0 CYC: 42"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            state().to_json(),
+            "{\"pc\":336,\"sp\":65534,\"a\":1,\"f\":176,\"b\":0,\"c\":19,\"d\":0,\"e\":216,\"h\":1,\"l\":77,\"ime\":true,\"halted\":false,\"cycles\":42}"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ *  Timer and divider, 0xFF04-0xFF07.
+ *
+ *  DIV is the upper byte of a 16-bit counter incremented every T-cycle.
+ *  TIMA increments on the falling edge of the counter bit selected by
+ *  TAC (gated by the TAC enable bit), and reloads from TMA on overflow.
+ *
+ *      TAC bits 0-1    TIMA frequency  Counter bit
+ *          00          4096 Hz         9
+ *          01          262144 Hz       3
+ *          10          65536 Hz        5
+ *          11          16384 Hz        7
+ */
+#[derive(Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Timer {
+    pub fn get(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => 0xF8 | self.tac,
+        }
+    }
+
+    /*
+     *  Set the internal counter directly, e.g. to its post-boot value.
+     */
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    /*
+     *  Write a timer register. Returns true if TIMA overflowed, which can
+     *  happen when the write causes a falling edge on the selected bit.
+     */
+    pub fn set(&mut self, addr: u16, val: u8) -> bool {
+        let before = self.signal();
+        match addr {
+            0xFF04 => self.counter = 0,
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            _ => self.tac = val & 0x07,
+        }
+        before && !self.signal() && self.increment()
+    }
+
+    /*
+     *  Advance by one M-cycle. Returns true if TIMA overflowed.
+     */
+    pub fn tick(&mut self) -> bool {
+        let before = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        before && !self.signal() && self.increment()
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        (self.tac & 0x04) != 0 && (self.counter & (1 << bit)) != 0
+    }
+
+    fn increment(&mut self) -> bool {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = if overflow { self.tma } else { tima };
+        overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+
+    #[test]
+    fn test_div() {
+        let mut a = Timer::default();
+        for _ in 0..64 {
+            a.tick();
+        }
+        assert_eq!(a.get(0xFF04), 1);
+        a.set(0xFF04, 0x55);
+        assert_eq!(a.get(0xFF04), 0);
+    }
+
+    #[test]
+    fn test_tima_overflow() {
+        let mut a = Timer::default();
+        a.set(0xFF06, 0xF0);
+        a.set(0xFF05, 0xFF);
+        a.set(0xFF07, 0x05); // Enabled, every 4 M-cycles
+        assert!(!a.tick());
+        assert!(!a.tick());
+        assert!(!a.tick());
+        assert!(a.tick());
+        assert_eq!(a.get(0xFF05), 0xF0);
+    }
+}
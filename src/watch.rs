@@ -0,0 +1,195 @@
+/*
+ *  Memory access hooks, for debuggers and scripting.
+ *
+ *  A hook fires on CPU reads, writes or opcode fetches (Execute) within
+ *  an address range, optionally only while a given bank is mapped there:
+ *  the ROM bank at 0000-7FFF, the external RAM bank at A000-BFFF or the
+ *  WRAM bank at D000-DFFF (and its echo). Everything else is bank 0.
+ *  A hook either calls a function or, as a watchpoint, records the
+ *  access for the caller to pick up after the step.
+ *
+ *  Accesses made by OAM DMA, and peeks through MMUnit::get, don't fire
+ *  hooks. Operand bytes following an opcode are reads, not fetches.
+ */
+use std::ops::RangeInclusive;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/*
+ *  An access that matched a hook.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub access: Access,
+    pub addr: u16,
+    pub bank: u16, // ROM, external RAM or WRAM bank mapped at addr
+    pub val: u8,   // Byte read, written or fetched
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+enum Action {
+    Call(Box<dyn FnMut(&Hit)>),
+    Break,
+}
+
+struct Hook {
+    id: HookId,
+    access: Access,
+    range: RangeInclusive<u16>,
+    bank: Option<u16>,
+    action: Action,
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    kinds: u8, // One bit per Access with a hook registered
+    next_id: u32,
+    hit: Option<Hit>,
+}
+
+impl Hooks {
+    /*
+     *  Whether any hook is registered for access. Checked on every bus
+     *  access so it has to stay cheap.
+     */
+    #[inline]
+    pub fn watching(&self, access: Access) -> bool {
+        self.kinds & access.mask() != 0
+    }
+
+    pub fn add<F: FnMut(&Hit) + 'static>(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+        f: F,
+    ) -> HookId {
+        self.push(access, range, bank, Action::Call(Box::new(f)))
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+    ) -> HookId {
+        self.push(access, range, bank, Action::Break)
+    }
+
+    fn push(
+        &mut self,
+        access: Access,
+        range: RangeInclusive<u16>,
+        bank: Option<u16>,
+        action: Action,
+    ) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push(Hook {
+            id,
+            access,
+            range,
+            bank,
+            action,
+        });
+        self.kinds |= access.mask();
+        id
+    }
+
+    /*
+     *  Unregister a hook. Returns false if there was no such hook.
+     */
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|h| h.id != id);
+        self.kinds = self.hooks.iter().fold(0, |k, h| k | h.access.mask());
+        self.hooks.len() != len
+    }
+
+    /*
+     *  Run the hooks matching an access. The first watchpoint hit is kept
+     *  until taken.
+     */
+    pub fn fire(&mut self, hit: Hit) {
+        for h in self.hooks.iter_mut() {
+            if h.access != hit.access
+                || !h.range.contains(&hit.addr)
+                || h.bank.is_some_and(|b| b != hit.bank)
+            {
+                continue;
+            }
+            match &mut h.action {
+                Action::Call(f) => f(&hit),
+                Action::Break => {
+                    if self.hit.is_none() {
+                        self.hit = Some(hit);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<Hit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn hit(access: Access, addr: u16, bank: u16) -> Hit {
+        Hit {
+            access,
+            addr,
+            bank,
+            val: 0,
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut a = Hooks::default();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&seen);
+        a.add(Access::Write, 0x4000..=0x7FFF, Some(2), move |h| {
+            log.borrow_mut().push(h.addr)
+        });
+        assert!(a.watching(Access::Write));
+        assert!(!a.watching(Access::Read));
+        a.fire(hit(Access::Write, 0x4000, 2));
+        a.fire(hit(Access::Write, 0x4001, 3)); // Other bank
+        a.fire(hit(Access::Write, 0x3FFF, 2)); // Out of range
+        a.fire(hit(Access::Read, 0x4002, 2)); // Other access
+        assert_eq!(*seen.borrow(), vec![0x4000]);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut a = Hooks::default();
+        let id = a.add_watchpoint(Access::Execute, 0x0150..=0x0150, None);
+        a.fire(hit(Access::Execute, 0x0150, 0));
+        a.fire(hit(Access::Execute, 0x0150, 1));
+        assert_eq!(a.take_hit(), Some(hit(Access::Execute, 0x0150, 0)));
+        assert_eq!(a.take_hit(), None);
+        assert!(a.remove(id));
+        assert!(!a.remove(id));
+        assert!(!a.watching(Access::Execute));
+    }
+}